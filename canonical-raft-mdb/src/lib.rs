@@ -0,0 +1,343 @@
+//! An LMDB backed storage for canonical-raft, akin to [the `MDBStore` of HashiCorp](https://github.com/hashicorp/raft-mdb).
+//!
+//! Every write is done in its own transaction and is durable once it returns,
+//! it can be used as the storage half of any `raft_io` that accepts a `Storage`.
+
+use std::convert::TryInto;
+use std::io::{self, Read};
+use std::path::Path;
+
+use canonical_raft::store::{Log, LogKind, LogStore, SnapshotData, SnapshotMeta, SnapshotStore, StableStore};
+use canonical_raft::{RaftError, Result};
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, RoTransaction, Transaction, WriteFlags};
+
+/// The default maximum size of the database, 128MiB.
+pub const DEFAULT_MAP_SIZE: usize = 128 * 1024 * 1024;
+
+const LOGS_DB: &str = "logs";
+const CONF_DB: &str = "conf";
+const SNAPSHOT_DB: &str = "snapshot";
+
+const SNAPSHOT_META_KEY: &[u8] = b"meta";
+const SNAPSHOT_DATA_PREFIX: &[u8] = b"data";
+
+/// A `LogStore`, `StableStore` and `SnapshotStore` persisted in an LMDB environment.
+pub struct MdbStore {
+    env: Environment,
+    // The log entries keyed by their big endian index, to be sorted by index.
+    logs: Database,
+    conf: Database,
+    snapshot: Database,
+}
+
+impl MdbStore {
+    /// Opens the store in the given directory, that must already exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MdbStore> {
+        MdbStore::open_with_map_size(path, DEFAULT_MAP_SIZE)
+    }
+
+    /// Opens the store in the given directory, that can grow up to `map_size` bytes.
+    pub fn open_with_map_size<P: AsRef<Path>>(path: P, map_size: usize) -> Result<MdbStore> {
+        let env = Environment::new()
+            .set_max_dbs(3)
+            .set_map_size(map_size)
+            .open(path.as_ref())
+            .map_err(mdb_error)?;
+
+        let logs = env.create_db(Some(LOGS_DB), DatabaseFlags::empty()).map_err(mdb_error)?;
+        let conf = env.create_db(Some(CONF_DB), DatabaseFlags::empty()).map_err(mdb_error)?;
+        let snapshot = env.create_db(Some(SNAPSHOT_DB), DatabaseFlags::empty()).map_err(mdb_error)?;
+
+        Ok(MdbStore { env, logs, conf, snapshot })
+    }
+
+    fn edge_index(&self, op: lmdb_sys::MDB_cursor_op) -> Result<u64> {
+        let txn = self.env.begin_ro_txn().map_err(mdb_error)?;
+        let cursor = txn.open_ro_cursor(self.logs).map_err(mdb_error)?;
+        match cursor.get(None, None, op) {
+            Ok((Some(key), _)) => decode_index(key),
+            Ok((None, _)) | Err(lmdb::Error::NotFound) => Ok(0),
+            Err(e) => Err(mdb_error(e)),
+        }
+    }
+}
+
+impl LogStore for MdbStore {
+    fn first_index(&self) -> Result<u64> {
+        self.edge_index(lmdb_sys::MDB_FIRST)
+    }
+
+    fn last_index(&self) -> Result<u64> {
+        self.edge_index(lmdb_sys::MDB_LAST)
+    }
+
+    fn get_log(&self, index: u64) -> Result<Option<Log>> {
+        let txn = self.env.begin_ro_txn().map_err(mdb_error)?;
+        match txn.get(self.logs, &index.to_be_bytes()) {
+            Ok(bytes) => decode_log(index, bytes).map(Some),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(mdb_error(e)),
+        }
+    }
+
+    fn store_logs(&mut self, logs: &[Log]) -> Result<()> {
+        let mut txn = self.env.begin_rw_txn().map_err(mdb_error)?;
+        for log in logs {
+            let bytes = encode_log(log);
+            txn.put(self.logs, &log.index.to_be_bytes(), &bytes, WriteFlags::empty()).map_err(mdb_error)?;
+        }
+        txn.commit().map_err(mdb_error)
+    }
+
+    fn delete_range(&mut self, min: u64, max: u64) -> Result<()> {
+        let mut txn = self.env.begin_rw_txn().map_err(mdb_error)?;
+
+        let mut keys = Vec::new();
+        let mut cursor = txn.open_ro_cursor(self.logs).map_err(mdb_error)?;
+        for result in cursor.iter_from(min.to_be_bytes()) {
+            let (key, _) = result.map_err(mdb_error)?;
+            if decode_index(key)? > max {
+                break;
+            }
+            keys.push(key.to_vec());
+        }
+        drop(cursor);
+
+        for key in keys {
+            txn.del(self.logs, &key, None).map_err(mdb_error)?;
+        }
+        txn.commit().map_err(mdb_error)
+    }
+}
+
+impl StableStore for MdbStore {
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut txn = self.env.begin_rw_txn().map_err(mdb_error)?;
+        txn.put(self.conf, &key, &value, WriteFlags::empty()).map_err(mdb_error)?;
+        txn.commit().map_err(mdb_error)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let txn = self.env.begin_ro_txn().map_err(mdb_error)?;
+        match txn.get(self.conf, &key) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(mdb_error(e)),
+        }
+    }
+}
+
+impl SnapshotStore for MdbStore {
+    fn create(&mut self, meta: SnapshotMeta, chunks: &[&[u8]]) -> Result<()> {
+        let mut txn = self.env.begin_rw_txn().map_err(mdb_error)?;
+        // The chunks of the previous snapshot may outnumber the new ones.
+        txn.clear_db(self.snapshot).map_err(mdb_error)?;
+        let meta = encode_snapshot_meta(&meta);
+        txn.put(self.snapshot, &SNAPSHOT_META_KEY, &meta, WriteFlags::empty()).map_err(mdb_error)?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            txn.put(self.snapshot, &snapshot_data_key(i as u64), chunk, WriteFlags::empty()).map_err(mdb_error)?;
+        }
+        txn.commit().map_err(mdb_error)
+    }
+
+    fn latest(&self) -> Result<Option<(SnapshotMeta, SnapshotData<'_>)>> {
+        let txn = self.env.begin_ro_txn().map_err(mdb_error)?;
+        let meta = match snapshot_meta(&txn, self.snapshot)? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+
+        // The chunks are only measured here, they are read as the data is consumed.
+        let (mut n_chunks, mut size) = (0, 0);
+        loop {
+            match txn.get(self.snapshot, &snapshot_data_key(n_chunks)) {
+                Ok(chunk) => {
+                    n_chunks += 1;
+                    size += chunk.len() as u64;
+                },
+                Err(lmdb::Error::NotFound) => break,
+                Err(e) => return Err(mdb_error(e)),
+            }
+        }
+
+        let reader = ChunkReader { txn, db: self.snapshot, n_chunks, next: 0, offset: 0 };
+        Ok(Some((meta, SnapshotData::new(size, reader))))
+    }
+
+    fn latest_meta(&self) -> Result<Option<SnapshotMeta>> {
+        let txn = self.env.begin_ro_txn().map_err(mdb_error)?;
+        snapshot_meta(&txn, self.snapshot)
+    }
+}
+
+fn snapshot_meta(txn: &RoTransaction<'_>, db: Database) -> Result<Option<SnapshotMeta>> {
+    match txn.get(db, &SNAPSHOT_META_KEY) {
+        Ok(bytes) => decode_snapshot_meta(bytes).map(Some),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(mdb_error(e)),
+    }
+}
+
+/// Reads the chunks of a snapshot from the transaction it was measured in.
+struct ChunkReader<'env> {
+    txn: RoTransaction<'env>,
+    db: Database,
+    n_chunks: u64,
+    next: u64,
+    // The position in the next chunk.
+    offset: usize,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.next < self.n_chunks {
+            // The chunks were all found in this transaction, failing
+            // to read one of them again means the database is corrupted.
+            let chunk = match self.txn.get(self.db, &snapshot_data_key(self.next)) {
+                Ok(chunk) => chunk,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+
+            if self.offset < chunk.len() {
+                let n = buf.len().min(chunk.len() - self.offset);
+                buf[..n].copy_from_slice(&chunk[self.offset..self.offset + n]);
+                self.offset += n;
+                return Ok(n);
+            }
+
+            self.next += 1;
+            self.offset = 0;
+        }
+
+        Ok(0)
+    }
+}
+
+fn mdb_error(error: lmdb::Error) -> RaftError {
+    match error {
+        lmdb::Error::MapFull => RaftError::NoSpace(Some(error.to_string())),
+        lmdb::Error::Corrupted | lmdb::Error::Panic => RaftError::Corrupt(Some(error.to_string())),
+        error => RaftError::IoErr(Some(error.to_string())),
+    }
+}
+
+fn corrupt(what: &str) -> RaftError {
+    RaftError::Corrupt(Some(format!("invalid {} stored in the database", what)))
+}
+
+fn decode_index(bytes: &[u8]) -> Result<u64> {
+    bytes.try_into().map(u64::from_be_bytes).map_err(|_| corrupt("log index"))
+}
+
+/// The chunks of the snapshot data are keyed by their big endian position, to be sorted.
+fn snapshot_data_key(i: u64) -> Vec<u8> {
+    [SNAPSHOT_DATA_PREFIX, &i.to_be_bytes()].concat()
+}
+
+/// A log entry is stored as its term, its kind and its data.
+fn encode_log(log: &Log) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(10 + log.data.len());
+    bytes.extend_from_slice(&log.term.to_le_bytes());
+    bytes.extend_from_slice(&log.kind.to_raw().to_le_bytes());
+    bytes.extend_from_slice(&log.data);
+    bytes
+}
+
+fn decode_log(index: u64, bytes: &[u8]) -> Result<Log> {
+    if bytes.len() < 10 {
+        return Err(corrupt("log entry"));
+    }
+
+    let term = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+    let kind = LogKind::from_raw(u16::from_le_bytes(bytes[8..10].try_into().unwrap()))?;
+    let data = bytes[10..].to_vec();
+
+    Ok(Log { index, term, kind, data })
+}
+
+/// The snapshot metadata is stored as its indexes and term followed by the configuration.
+fn encode_snapshot_meta(meta: &SnapshotMeta) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(24 + meta.configuration.len());
+    bytes.extend_from_slice(&meta.index.to_le_bytes());
+    bytes.extend_from_slice(&meta.term.to_le_bytes());
+    bytes.extend_from_slice(&meta.configuration_index.to_le_bytes());
+    bytes.extend_from_slice(&meta.configuration);
+    bytes
+}
+
+fn decode_snapshot_meta(bytes: &[u8]) -> Result<SnapshotMeta> {
+    if bytes.len() < 24 {
+        return Err(corrupt("snapshot metadata"));
+    }
+
+    Ok(SnapshotMeta {
+        index: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+        term: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        configuration_index: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        configuration: bytes[24..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use canonical_raft::store::CURRENT_TERM_KEY;
+
+    use super::*;
+
+    #[test]
+    fn persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let mut store = MdbStore::open(dir.path()).unwrap();
+            let logs: Vec<_> = (1..=5)
+                .map(|index| Log { index, term: 2, kind: LogKind::Command, data: vec![index as u8] })
+                .collect();
+            store.store_logs(&logs).unwrap();
+            store.delete_range(1, 2).unwrap();
+            store.set_u64(CURRENT_TERM_KEY, 2).unwrap();
+        }
+
+        let store = MdbStore::open(dir.path()).unwrap();
+        assert_eq!(store.first_index().unwrap(), 3);
+        assert_eq!(store.last_index().unwrap(), 5);
+        assert_eq!(store.get_log(4).unwrap().unwrap().data, vec![4]);
+        assert_eq!(store.get_u64(CURRENT_TERM_KEY).unwrap(), 2);
+        assert!(store.latest().unwrap().is_none());
+    }
+
+    fn read_latest(store: &MdbStore) -> Option<(SnapshotMeta, Vec<u8>)> {
+        store.latest().unwrap().map(|(meta, mut data)| {
+            let mut bytes = Vec::new();
+            data.read_to_end(&mut bytes).unwrap();
+            assert_eq!(bytes.len() as u64, data.size());
+            (meta, bytes)
+        })
+    }
+
+    #[test]
+    fn snapshot_chunks_replace_the_previous_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = MdbStore::open(dir.path()).unwrap();
+
+        let meta = |index| SnapshotMeta { index, term: 1, configuration: vec![1, 2], configuration_index: 1 };
+        store.create(meta(10), &[&b"ab"[..], b"cd", b"e"]).unwrap();
+        assert_eq!(read_latest(&store), Some((meta(10), b"abcde".to_vec())));
+
+        store.create(meta(20), &[&b"fg"[..]]).unwrap();
+        assert_eq!(read_latest(&store), Some((meta(20), b"fg".to_vec())));
+        assert_eq!(store.latest_meta().unwrap(), Some(meta(20)));
+    }
+
+    #[test]
+    fn snapshot_without_chunks_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = MdbStore::open(dir.path()).unwrap();
+
+        let meta = SnapshotMeta { index: 10, term: 1, configuration: vec![1, 2], configuration_index: 1 };
+        store.create(meta.clone(), &[&b"ab"[..]]).unwrap();
+        store.create(meta.clone(), &[]).unwrap();
+        assert_eq!(read_latest(&store), Some((meta.clone(), Vec::new())));
+        assert_eq!(store.latest_meta().unwrap(), Some(meta));
+    }
+}
@@ -1,9 +1,12 @@
 use std::convert::TryInto;
-use std::ffi::{CStr, CString};
-use std::{mem, ptr, str};
-
-use canonical_raft_sys::*;
-use libc::{c_void, c_char, c_int, c_uint};
+use std::ffi::CStr;
+use std::io::{Read, Write};
+use std::{mem, ptr};
+
+use canonical_raft::{Configuration, Fsm, RaftError, Raft, State, UvIo};
+use futures::executor::LocalPool;
+use futures::task::LocalSpawnExt;
+use libc::c_void;
 use libuv_sys2::{UV_VERSION_MAJOR, UV_VERSION_MINOR, UV_VERSION_PATCH};
 use libuv_sys2::{uv_handle_t, uv_timer_t};
 use libuv_sys2::{uv_default_loop, uv_run_mode_UV_RUN_DEFAULT};
@@ -18,125 +21,47 @@ const N_SERVERS: usize = 3;
 // Apply a new entry every 125 milliseconds
 const APPLY_RATE: u64 = 125;
 
-// pub struct RaftBox<T>(Box<T>);
-
-// pub trait Fsm {
-//     fn new() -> RaftBox<Self>;
-
-//     fn apply(&mut self, buffer: &[u8]) -> Result<(), ()>;
-
-//     fn snapshot(&self) -> Result<RaftBox<[u8]>, ()>;
-
-//     fn restore(&mut self, buffer: &[u8]) -> Result<(), ()>;
-// }
-
 /********************************************************************
  *
  * Sample application FSM that just increases a counter.
  *
  ********************************************************************/
 
-unsafe fn slice_from_buf<'a>(buf: raft_buffer) -> &'a [u8] {
-    std::slice::from_raw_parts(buf.base as *const u8, buf.len)
-}
-
-#[repr(C)]
-struct Fsm {
+struct Counter {
     count: u64,
 }
 
-unsafe extern "C" fn fsm_apply(
-    fsm: *mut raft_fsm,
-    buf: *const raft_buffer,
-    result: *mut *mut c_void,
-) -> c_int
-{
-    let f: &mut Fsm = mem::transmute((*fsm).data);
-
-    println!("Hello fsm_apply");
-
-    let buf = slice_from_buf(*buf);
-    if buf.len() != mem::size_of::<u64>() {
-        return RAFT_MALFORMED;
-    }
-
-    f.count += buf.try_into().map(u64::from_ne_bytes).unwrap();
-    (*result) = &mut f.count as *mut _ as *mut c_void;
-
-    println!("count after apply: {}", f.count);
+impl Fsm for Counter {
+    type Output = u64;
 
-    return 0;
-}
+    fn apply(&mut self, command: &[u8]) -> canonical_raft::Result<u64> {
+        println!("Hello fsm_apply");
 
-unsafe extern "C" fn fsm_snapshot(
-    fsm: *mut raft_fsm,
-    bufs: *mut *mut raft_buffer,
-    n_bufs: *mut c_uint,
-) -> c_int
-{
-    let f: &mut Fsm = mem::transmute((*fsm).data);
+        let increment = command.try_into().map(u64::from_ne_bytes);
+        let increment = increment.map_err(|_| RaftError::Malformed(None))?;
+        self.count += increment;
 
-    println!("Hello fsm_snapshot (count {})", f.count);
+        println!("count after apply: {}", self.count);
 
-    *n_bufs = 1;
-    *bufs = raft_malloc(mem::size_of::<raft_buffer>()) as *mut raft_buffer;
-    if (*bufs).is_null() {
-        return RAFT_NOMEM;
+        Ok(self.count)
     }
 
-    // (*bufs)[0]
-    (**bufs).len = mem::size_of::<u64>();
-    (**bufs).base = raft_malloc((**bufs).len);
-
-    if (**bufs).base.is_null() {
-        raft_free((*bufs) as *mut _); // avoid leaking!
-        return RAFT_NOMEM;
+    fn snapshot<W: Write>(&self, mut writer: W) -> canonical_raft::Result<()> {
+        println!("Hello fsm_snapshot (count {})", self.count);
+        writer.write_all(&self.count.to_ne_bytes())?;
+        Ok(())
     }
-    ptr::copy_nonoverlapping(&f.count, (**bufs).base as *mut u64, 1);
 
-    return 0;
-}
+    fn restore<R: Read>(&mut self, mut reader: R) -> canonical_raft::Result<()> {
+        println!("Hello fsm_restore");
 
-unsafe extern "C" fn fsm_restore(fsm: *mut raft_fsm, buf: *mut raft_buffer) -> c_int {
-    let f: &mut Fsm = mem::transmute((*fsm).data);
+        let mut count = [0; 8];
+        reader.read_exact(&mut count)?;
+        self.count = u64::from_ne_bytes(count);
 
-    println!("Hello fsm_restore");
+        println!("bye! fsm_restore (count {})", self.count);
 
-    let slice = slice_from_buf(*buf);
-    if slice.len() != mem::size_of::<u64>() {
-        return RAFT_MALFORMED;
-    }
-
-    f.count = slice.try_into().map(u64::from_ne_bytes).unwrap();
-
-    raft_free((*buf).base);
-
-    println!("bye! fsm_restore (count {})", f.count);
-
-    return 0;
-}
-
-unsafe extern "C" fn fsm_init(fsm: *mut raft_fsm) -> c_int {
-    let f: *mut Fsm = raft_malloc(mem::size_of::<Fsm>()) as *mut _;
-    if f.is_null() {
-        return RAFT_NOMEM;
-    }
-
-    (*f).count = 0;
-    (*fsm).version = 1;
-    (*fsm).data = f as *mut c_void;
-    (*fsm).apply = Some(fsm_apply);
-    (*fsm).snapshot = Some(fsm_snapshot);
-    (*fsm).restore = Some(fsm_restore);
-
-    return 0;
-}
-
-unsafe extern "C" fn fsm_close(fsm: *mut raft_fsm) {
-    println!("Hello fsm_close");
-
-    if !(*fsm).data.is_null() {
-        raft_free((*fsm).data);
+        Ok(())
     }
 }
 
@@ -147,53 +72,25 @@ unsafe extern "C" fn fsm_close(fsm: *mut raft_fsm) {
  *
  ********************************************************************/
 
-unsafe extern "C" fn server_raft_close_cb(raft: *mut raft) {
-    let s: &mut Server = mem::transmute((*raft).data);
-
-    raft_uv_close(&mut s.io);
-    raft_uv_tcp_close(&mut s.transport);
-    fsm_close(&mut s.fsm);
-
-    if let Some(close_cb) = s.close_cb {
-        close_cb(s);
-    }
-}
-
-unsafe extern "C" fn server_transfer_cb(req: *mut raft_transfer) {
-    let s: &mut Server = mem::transmute((*req).data);
-
-    let mut id: raft_id = 0;
-    let mut address: *const c_char = ptr::null();
-    raft_leader(&mut s.raft, &mut id, &mut address);
-    raft_close(&mut s.raft, Some(server_raft_close_cb));
-}
-
 /// Final callback in the shutdown sequence, invoked after the timer handle has been closed.
 unsafe extern "C" fn server_timer_close_cb(handle: *mut uv_handle_t) {
     let s: &mut Server = mem::transmute((*handle).data);
 
-    if !s.raft.data.is_null() {
-        if s.raft.state == RAFT_LEADER as u16 {
-            let rv = raft_transfer(&mut s.raft, &mut s.transfer, 0, Some(server_transfer_cb));
-            if rv == 0 { return }
-        }
-        raft_close(&mut s.raft, Some(server_raft_close_cb));
+    // Dropping the raft handle starts its close sequence.
+    s.raft = None;
+
+    if let Some(close_cb) = s.close_cb {
+        close_cb(s);
     }
 }
 
-#[repr(C)]
 struct Server {
     data: *mut c_void,               // User data context.
-    loop_: *mut uv_loop_s,           // UV loop.
     timer: uv_timer_s,               // To periodically apply a new entry.
-    dir: *const c_char,              // Data dir of UV I/O backend.
-    transport: raft_uv_transport,    // UV I/O backend transport.
-    io: raft_io,                     // UV I/O backend.
-    fsm: raft_fsm,                   // Sample application FSM.
     id: u32,                         // Raft instance ID.
-    address: [u8; 64],               // Raft instance address.
-    raft: raft,                      // Raft instance.
-    transfer: raft_transfer,         // Transfer leadership request.
+    raft: Option<Raft<Counter>>,     // Raft instance, along with its I/O backend and FSM.
+    pool: LocalPool,                 // Drives the futures of the applied commands.
+    closing: bool,                   // Whether the shutdown sequence started.
     close_cb: Option<ServerCloseCb>, // Optional close callback.
 }
 
@@ -201,200 +98,105 @@ struct Server {
 type ServerCloseCb = unsafe extern "C" fn(*mut Server);
 
 /// Initialize the example server struct, without starting it yet.
-unsafe fn server_init(
-    s: *mut Server,
-    loop_: *mut uv_loop_s,
-    dir: *const c_char,
-    id: u32,
-) {
-    // // Seed the random generator
-    // timespec_get(&now, TIME_UTC);
-    // srandom((unsigned)(now.tv_nsec ^ now.tv_sec));
-    // unimplemented!();
-
-    ptr::write_bytes(s, 0, 1);
-
-    (*s).loop_ = loop_;
+unsafe fn server_init(loop_: *mut uv_loop_s, dir: &str, id: u32) -> Box<Server> {
+    let mut s = Box::new(Server {
+        data: ptr::null_mut(),
+        timer: mem::zeroed(),
+        id,
+        raft: None,
+        pool: LocalPool::new(),
+        closing: false,
+        close_cb: None,
+    });
 
     // Add a timer to periodically try to propose a new entry.
-    let rv = uv_timer_init((*s).loop_, &mut (*s).timer);
-    if rv != 0 {
-        // Logf(s->id, "uv_timer_init(): %s", uv_strerror(rv));
-        // goto err;
-        panic!("Whoops...");
-    }
-    (*s).timer.data = s as *mut _;
-
-    // Initialize the TCP-based RPC transport.
-    let rv = raft_uv_tcp_init(&mut (*s).transport, (*s).loop_);
-    if rv != 0 {
-        // goto err;
-        panic!("Ouïe!!!");
-    }
-
-    // Initialize the libuv-based I/O backend.
-    let rv = raft_uv_init(&mut (*s).io, (*s).loop_, dir, &mut (*s).transport);
-    if rv != 0 {
-        // Logf(s->id, "raft_uv_init(): %s", s->io.errmsg);
-        // goto err_after_uv_tcp_init;
-        panic!("Aïeeeuux!!!");
-    }
-
-    // Initialize the finite state machine.
-    let rv = fsm_init(&mut (*s).fsm);
+    let rv = uv_timer_init(loop_, &mut s.timer);
     if rv != 0 {
-        // Logf(s->id, "FsmInit(): %s", raft_strerror(rv));
-        // goto err_after_uv_init;
-        panic!("Aïe");
+        let uverror = CStr::from_ptr(uv_strerror(rv));
+        panic!("{}: uv_timer_init(): {:?}", id, uverror);
     }
+    s.timer.data = &mut *s as *mut Server as *mut c_void;
 
-    // Save the server ID.
-    (*s).id = id;
+    // Initialize the libuv-based I/O backend and its TCP-based RPC transport.
+    let io = match UvIo::new(loop_, dir) {
+        Ok(io) => io,
+        Err(e) => panic!("{}: raft_uv_init(): {}", id, e),
+    };
 
     // Render the address.
-    let address = CString::new(format!("127.0.0.1:900{}", id)).unwrap();
-
-    let bytes = address.as_bytes_with_nul();
-    (*s).address[..bytes.len()].copy_from_slice(bytes);
+    let address = format!("127.0.0.1:900{}", id);
 
-    let rv = raft_init(&mut (*s).raft, &mut (*s).io, &mut (*s).fsm, id.into(), address.as_ptr());
-    if rv != 0 {
-        let errmsg = unsafe {
-            // This is safe since the error messages returned from mdb_strerror are static.
-            let err: *const c_char = raft_errmsg(&mut (*s).raft);
-            str::from_utf8_unchecked(CStr::from_ptr(err).to_bytes())
-        };
-        eprintln!("{}: raft_init(): {}", id, errmsg);
-        panic!("AAA");
-    }
-    (*s).raft.data = s as *mut _;
+    // Initialize the raft instance along with the finite state machine.
+    let mut raft = match Raft::new(io, Counter { count: 0 }, id.into(), &address) {
+        Ok(raft) => raft,
+        Err(e) => panic!("{}: raft_init(): {}", id, e),
+    };
 
     // Bootstrap the initial configuration if needed.
-    let mut configuration = mem::zeroed();
-    raft_configuration_init(&mut configuration);
-
-    for i in 0..N_SERVERS {
-        let server_id = (i + 1) as u64;
-        let address = CString::new(format!("127.0.0.1:900{}", server_id)).unwrap();
-        let rv = raft_configuration_add(&mut configuration, server_id, address.as_ptr(), RAFT_VOTER);
-        if rv != 0 {
-            // Logf(s->id, "raft_configuration_add(): %s", raft_strerror(rv));
-            // goto err_after_configuration_init;
-            panic!("Ouuups...");
-        }
-    }
-
-    let rv = raft_bootstrap(&mut (*s).raft, &configuration);
-    if rv != 0 && rv != RAFT_CANTBOOTSTRAP {
-        // goto err_after_configuration_init;
-        dbg!(rv);
-        panic!("Bubuut?!");
+    let configuration = (1..=N_SERVERS as u64)
+        .fold(Configuration::builder(), |builder, i| builder.voter(i, format!("127.0.0.1:900{}", i)))
+        .build()
+        .unwrap();
+
+    match raft.bootstrap(&configuration) {
+        Ok(()) | Err(RaftError::CantBootstrap(_)) => (),
+        Err(e) => panic!("{}: raft_bootstrap(): {}", id, e),
     }
 
-    raft_configuration_close(&mut configuration);
-    drop(configuration);
+    raft.set_snapshot_threshold(64);
+    raft.set_snapshot_trailing(16);
 
-    raft_set_snapshot_threshold(&mut (*s).raft, 64);
-    raft_set_snapshot_trailing(&mut (*s).raft, 16);
+    s.raft = Some(raft);
 
-    (*s).transfer.data = s as *mut _;
-
-    // err_after_configuration_init:
-    //     raft_configuration_close(&configuration);
-    // err_after_fsm_init:
-    //     FsmClose(&s->fsm);
-    // err_after_uv_init:
-    //     raft_uv_close(&s->io);
-    // err_after_uv_tcp_init:
-    //     raft_uv_tcp_close(&s->transport);
-    // err:
-    //     return rv;
-}
-
-/// Called after a request to apply a new command to the FSM has been completed.
-unsafe extern "C" fn server_apply_cb(req: *mut raft_apply, status: i32, result: *mut c_void) {
-    let s: &mut Server = mem::transmute((*req).data);
-
-    raft_free(req as *mut _);
-
-    println!("{}: Hello server_apply_cb (status: {})", s.id, status);
-
-    if status != 0 {
-        if status != RAFT_LEADERSHIPLOST as i32 {
-            let errmsg = unsafe {
-                // This is safe since the error messages returned from mdb_strerror are static.
-                let err: *const c_char = raft_errmsg(&mut (*s).raft);
-                str::from_utf8_unchecked(CStr::from_ptr(err).to_bytes())
-            };
-            println!("{}: raft_apply() callback: {} ({})", s.id, errmsg, status);
-        }
-        return;
-    }
-
-    let count = *(result as *const i32);
-
-    // if count % 100 == 0 {
-        println!("{}: count {}", s.id, count);
-    // }
+    s
 }
 
 /// Called periodically every APPLY_RATE milliseconds.
 unsafe extern "C" fn server_timer_cb(timer: *mut uv_timer_t) {
     let s: &mut Server = mem::transmute((*timer).data);
 
-    if s.raft.state != RAFT_LEADER as u16 {
-        // println!("{}: not leader, skipping", s.id);
-        return;
-    }
+    // Report the results of the commands that completed since the last tick.
+    s.pool.run_until_stalled();
 
-    println!("{}: I am the leader", s.id);
-
-    let buf = raft_buffer {
-        len: mem::size_of::<u64>(),
-        base: raft_malloc(mem::size_of::<u64>()),
-    };
-
-    if buf.base.is_null() {
-        // Log(s->id, "serverTimerCb(): out of memory");
+    // The timer keeps ticking to drive the leadership transfer.
+    if s.closing {
         return;
     }
 
-    // write 1u64 into base
-    ptr::copy_nonoverlapping(&1u64, buf.base as *mut u64, 1);
-
-    let req = raft_malloc(mem::size_of::<raft_apply>()) as *mut raft_apply;
-    if req.is_null() {
-        // Log(s->id, "serverTimerCb(): out of memory");
+    let raft = s.raft.as_ref().unwrap();
+    if raft.state() != State::Leader {
+        // println!("{}: not leader, skipping", s.id);
         return;
     }
-    (*req).data = s as *mut Server as *mut c_void;
 
-    eprintln!("before raft_apply");
+    println!("{}: I am the leader", s.id);
 
-    let rv = raft_apply(&mut (*s).raft, req, &buf, 1, Some(server_apply_cb));
-    if rv != 0 {
-        // Logf(s->id, "raft_apply(): %s", raft_errmsg(&s->raft));
-        return;
-    }
+    let id = s.id;
+    let apply = raft.apply(1u64.to_ne_bytes().to_vec());
+    let task = async move {
+        match apply.await {
+            Ok((count, _index)) => println!("{}: count {}", id, count),
+            Err(RaftError::LeadershipLost(_)) => (),
+            Err(e) => println!("{}: raft_apply(): {}", id, e),
+        }
+    };
 
-    eprintln!("bye server_timer_cb");
+    s.pool.spawner().spawn_local(task).unwrap();
 }
 
 /// Start the example server.
-unsafe fn server_start(s: *mut Server) -> i32 {
-    println!("{}: starting", (*s).id);
+unsafe fn server_start(s: &mut Server) -> i32 {
+    println!("{}: starting", s.id);
 
-    let rv = raft_start(&mut (*s).raft);
-    if rv != 0 {
-        let errmsg = CStr::from_ptr(raft_errmsg(&mut (*s).raft));
-        eprintln!("{}: raft_start(): {} {:?}", (*s).id, rv, errmsg);
-        return rv;
+    if let Err(e) = s.raft.as_mut().unwrap().start() {
+        eprintln!("{}: raft_start(): {}", s.id, e);
+        return e.code();
     }
 
-    let rv = uv_timer_start(&mut (*s).timer, Some(server_timer_cb), 0, APPLY_RATE);
+    let rv = uv_timer_start(&mut s.timer, Some(server_timer_cb), 0, APPLY_RATE);
     if rv != 0 {
         let uverror = CStr::from_ptr(uv_strerror(rv));
-        eprintln!("{}: uv_timer_start(): {:?}", (*s).id, uverror);
+        eprintln!("{}: uv_timer_start(): {:?}", s.id, uverror);
         return rv;
     }
 
@@ -409,12 +211,30 @@ unsafe fn server_close(s: &mut Server, cb: ServerCloseCb) {
 
     // Close the timer asynchronously if it was successfully
     // initialized. Otherwise invoke the callback immediately.
-    if !s.timer.data.is_null() {
-        uv_close(&mut s.timer as *mut _ as *mut uv_handle_t, Some(server_timer_close_cb));
+    if s.timer.data.is_null() {
+        s.raft = None;
+        return cb(s);
+    }
+
+    s.closing = true;
+    let timer = &mut s.timer as *mut uv_timer_s as *mut uv_handle_t;
+
+    // Hand the leadership over to another server before stopping, the timer
+    // is closed once the transfer completed, whether it succeeded or not.
+    let raft = s.raft.as_ref().unwrap();
+    if raft.state() == State::Leader {
+        let id = s.id;
+        let transfer = raft.transfer_leadership(None);
+        let task = async move {
+            match transfer.await {
+                Ok((leader, address)) => println!("{}: transferred to {} at {}", id, leader, address),
+                Err(e) => println!("{}: raft_transfer(): {}", id, e),
+            }
+            uv_close(timer, Some(server_timer_close_cb));
+        };
+        s.pool.spawner().spawn_local(task).unwrap();
     } else {
-        if let Some(close_cb) = s.close_cb {
-            close_cb(s);
-        }
+        uv_close(timer, Some(server_timer_close_cb));
     }
 }
 
@@ -440,12 +260,15 @@ unsafe extern "C" fn main_sigint_cb(handle: *mut uv_signal_s, signum: i32) {
 
 fn main() {
     let mut args = std::env::args();
-    let dir = CString::new(args.nth(1).unwrap()).unwrap();
+    let dir = args.nth(1).unwrap();
     let id: u32 = args.next().unwrap().parse().unwrap();
 
     // Ignore SIGPIPE, see https://github.com/joyent/libuv/issues/1254
     unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) };
 
+    // Make the raft library allocate with the same allocator as the rest of the program.
+    unsafe { canonical_raft::heap::install_global().expect("no heap is installed yet") };
+
     // println!("UV_VERSION_MAJOR {}.{}.{}", UV_VERSION_MAJOR, UV_VERSION_MINOR, UV_VERSION_PATCH);
 
     // Initialize the libuv loop.
@@ -460,8 +283,7 @@ fn main() {
     // let mut loop_ = unsafe { loop_.assume_init() };
 
     // Initialize the example server.
-    let mut server = unsafe { mem::zeroed() };
-    unsafe { server_init(&mut server, loop_, dir.as_ptr(), id) };
+    let mut server = unsafe { server_init(loop_, &dir, id) };
     // let mut server = Server::new(loop_, dir.as_ptr(), id);
 
     // // Add a signal handler to stop the example server upon SIGINT.
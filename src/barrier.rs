@@ -0,0 +1,128 @@
+use std::future::Future;
+use std::mem;
+
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+use libc::{c_int, c_void};
+
+use crate::error::{RaftError, Result};
+use crate::state::{State, Status};
+
+/// Appends a barrier to the log of the given raft instance and, once every
+/// preceding entry is applied, calls `then` with the index of the barrier.
+///
+/// # Safety
+///
+/// `then` is called from the callback of the raft library, it must only
+/// access what stays valid until the raft instance is closed.
+pub(crate) unsafe fn barrier<T, G>(raft: *mut raft, then: G) -> impl Future<Output = Result<T>>
+where G: FnOnce(u64) -> T,
+{
+    let (sender, receiver) = oneshot::channel();
+    let result = submit_barrier(raft, then, sender);
+
+    async move {
+        result?;
+        match receiver.await {
+            Ok(result) => result,
+            Err(oneshot::Canceled) => Err(RaftError::Canceled(None)),
+        }
+    }
+}
+
+unsafe fn submit_barrier<T, G>(raft: *mut raft, then: G, sender: oneshot::Sender<Result<T>>) -> Result<()>
+where G: FnOnce(u64) -> T,
+{
+    if State::from_raw(raft_state(raft)) != State::Leader {
+        return Err(not_leader(raft));
+    }
+
+    let req = Box::into_raw(Box::new(BarrierRequest { raw: mem::zeroed(), then, sender }));
+    (*req).raw.data = req as *mut c_void;
+
+    let rv = raft_barrier(raft, &mut (*req).raw, Some(barrier_cb::<T, G>));
+    if rv != 0 {
+        drop(Box::from_raw(req));
+        return match RaftError::from_raft(rv, raft) {
+            RaftError::NotLeader(_) => Err(not_leader(raft)),
+            error => Err(error),
+        };
+    }
+
+    Ok(())
+}
+
+/// Returns a `NotLeader` error whose message names the current leader, if known.
+pub(crate) unsafe fn not_leader(raft: *mut raft) -> RaftError {
+    RaftError::NotLeader(Some(leader_hint(raft)))
+}
+
+/// Names the leader known to the given raft instance, if any.
+pub(crate) unsafe fn leader_hint(raft: *mut raft) -> String {
+    match Status::from_raw(raft).leader {
+        Some((id, address)) => format!("the leader is {} at {}", id, address),
+        None => "the leader is unknown".to_owned(),
+    }
+}
+
+struct BarrierRequest<T, G> {
+    raw: raft_barrier,
+    then: G,
+    sender: oneshot::Sender<Result<T>>,
+}
+
+unsafe extern "C" fn barrier_cb<T, G>(req: *mut raft_barrier, status: c_int)
+where G: FnOnce(u64) -> T,
+{
+    let req = Box::from_raw((*req).data as *mut BarrierRequest<T, G>);
+    let BarrierRequest { raw, then, sender } = *req;
+
+    let result = if status == 0 { Ok(then(raw.index)) } else { Err(RaftError::from_code(status)) };
+    let _ = sender.send(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::{cluster, Counter};
+
+    #[test]
+    fn read_waits_for_the_barrier() {
+        let mut fixture = cluster();
+
+        let applied = fixture.apply(0, 5u64.to_le_bytes().to_vec());
+        let fsm = fixture.fsm(0) as *const Counter;
+        let mut read = Box::pin(unsafe { barrier(fixture.raft(0), move |index| (index, (*fsm).0)) });
+
+        // Nothing is read before the barrier is committed and applied.
+        assert_eq!((&mut read).now_or_never(), None);
+
+        let mut result = None;
+        assert!(fixture.step_until(Duration::from_secs(2), |_| {
+            result = (&mut read).now_or_never();
+            result.is_some()
+        }));
+
+        let (output, index) = applied.now_or_never().unwrap().unwrap();
+        assert_eq!(output, 5);
+        let (barrier_index, count) = result.unwrap().unwrap();
+        assert!(barrier_index > index);
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn read_fails_on_a_follower() {
+        let mut fixture = cluster();
+        assert!(fixture.step_until(Duration::from_secs(2), |f| f.status(1).leader.is_some()));
+
+        let read = unsafe { barrier(fixture.raft(1), |index| index) };
+        match read.now_or_never() {
+            Some(Err(RaftError::NotLeader(Some(message)))) => assert!(message.starts_with("the leader is 1 at")),
+            other => panic!("unexpected read result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{mem, ptr};
+
+use bytes::Bytes;
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+use libc::{c_int, c_uint, c_void};
+
+use crate::error::{RaftError, Result};
+use crate::raft::command_buf;
+use crate::state::now;
+
+type Sender<T> = oneshot::Sender<Result<(T, u64)>>;
+
+/// The commands waiting to be submitted together in a single `raft_apply`.
+pub(crate) struct Batch<T> {
+    /// The time a command can wait for others, `None` disables the batching.
+    pub(crate) window: Option<Duration>,
+    /// The number of bytes that triggers the submission of the batch.
+    pub(crate) max_bytes: usize,
+    commands: Vec<(Bytes, Sender<T>)>,
+    bytes: usize,
+    // The time at which the first command of the batch arrived, in milliseconds.
+    started: raft_time,
+}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Batch<T> {
+        Batch { window: None, max_bytes: 1024 * 1024, commands: Vec::new(), bytes: 0, started: 0 }
+    }
+}
+
+impl<T> Batch<T> {
+    pub(crate) fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds a command to the batch, returns `true` if the batch must be submitted.
+    pub(crate) unsafe fn push(&mut self, raft: *mut raft, command: Bytes, sender: Sender<T>) -> bool {
+        if self.commands.is_empty() {
+            self.started = now(raft).unwrap_or(0);
+        }
+        self.bytes += command.len();
+        self.commands.push((command, sender));
+
+        self.bytes >= self.max_bytes || self.is_due(raft)
+    }
+
+    /// Returns `true` if the first command waited for the whole window.
+    pub(crate) unsafe fn is_due(&self, raft: *mut raft) -> bool {
+        match self.window {
+            Some(window) => !self.commands.is_empty() && now(raft).unwrap_or(0) >= self.started + window.as_millis() as raft_time,
+            None => !self.commands.is_empty(),
+        }
+    }
+
+    /// Submits the pending commands, their outputs are delivered through the captures.
+    pub(crate) unsafe fn submit(&mut self, raft: *mut raft, captures: *mut Captures<T>) {
+        let commands = mem::take(&mut self.commands);
+        self.bytes = 0;
+        if commands.is_empty() {
+            return;
+        }
+
+        let mut bufs = Vec::with_capacity(commands.len());
+        for (command, _) in &commands {
+            match command_buf(command) {
+                Some(buf) => bufs.push(buf),
+                None => {
+                    bufs.iter().for_each(|buf: &raft_buffer| raft_free(buf.base));
+                    fail(commands.into_iter().map(|(_, sender)| sender), RaftError::NoMem(None));
+                    return;
+                },
+            }
+        }
+
+        let req = Box::into_raw(Box::new(BatchRequest::<T> { raw: mem::zeroed(), captures, n: bufs.len() as u64 }));
+        (*req).raw.data = req as *mut c_void;
+
+        let rv = raft_apply(raft, &mut (*req).raw, bufs.as_ptr(), bufs.len() as c_uint, Some(batch_cb::<T>));
+        if rv != 0 {
+            let error = RaftError::from_raft(rv, raft);
+            drop(Box::from_raw(req));
+            // The raft library only takes the ownership of the buffers when it succeeds.
+            bufs.iter().for_each(|buf| raft_free(buf.base));
+            fail(commands.into_iter().map(|(_, sender)| sender), error);
+            return;
+        }
+
+        // Nothing is applied before we return to the event loop.
+        let first = (*req).raw.index;
+        for (i, (_, sender)) in commands.into_iter().enumerate() {
+            (*captures).pending.insert(first + i as u64, sender);
+        }
+    }
+}
+
+/// The senders waiting for the output of the entry at a given index.
+///
+/// The raft library only reports the output of the first entry of a `raft_apply`,
+/// the outputs of the others are captured by the `FsmAdapter` as they are applied.
+pub(crate) struct Captures<T> {
+    raft: *const raft,
+    pending: HashMap<u64, Sender<T>>,
+}
+
+impl<T> Default for Captures<T> {
+    fn default() -> Captures<T> {
+        Captures { raft: ptr::null(), pending: HashMap::new() }
+    }
+}
+
+impl<T> Captures<T> {
+    pub(crate) fn set_raft(&mut self, raft: *const raft) {
+        self.raft = raft;
+    }
+
+    /// Sends the output of the entry being applied if it was captured, gives it back otherwise.
+    pub(crate) unsafe fn capture(&mut self, output: T) -> Option<T> {
+        if self.pending.is_empty() || self.raft.is_null() {
+            return Some(output);
+        }
+
+        // The raft library updates the last applied index once the entry is applied.
+        let index = (*self.raft).last_applied + 1;
+        match self.pending.remove(&index) {
+            Some(sender) => {
+                let _ = sender.send(Ok((output, index)));
+                None
+            },
+            None => Some(output),
+        }
+    }
+
+    /// Fails every captured entry, the leadership was lost.
+    pub(crate) fn clear(&mut self, error: RaftError) {
+        fail(self.pending.drain().map(|(_, sender)| sender), error);
+    }
+}
+
+struct BatchRequest<T> {
+    raw: raft_apply,
+    captures: *mut Captures<T>,
+    n: u64,
+}
+
+unsafe extern "C" fn batch_cb<T>(req: *mut raft_apply, status: c_int, _result: *mut c_void) {
+    let req = Box::from_raw((*req).data as *mut BatchRequest<T>);
+    if status == 0 {
+        return;
+    }
+
+    let captures = &mut *req.captures;
+    let first = req.raw.index;
+    let senders = (first..first + req.n).filter_map(|index| captures.pending.remove(&index));
+    fail(senders, RaftError::from_code(status));
+}
+
+fn fail<T>(senders: impl Iterator<Item = Sender<T>>, error: RaftError) {
+    for sender in senders {
+        let _ = sender.send(Err(error.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::cluster;
+
+    #[test]
+    fn commands_are_applied_in_a_single_entry_batch() {
+        let mut fixture = cluster();
+
+        let raft = fixture.raft(0);
+        let captures = fixture.captures(0);
+        let last_index = unsafe { raft_last_index(raft) };
+
+        let mut batch = Batch { window: Some(Duration::from_secs(1)), ..Batch::default() };
+        let mut receivers = Vec::new();
+        for increment in 1..=3u64 {
+            let (sender, receiver) = oneshot::channel();
+            let command = Bytes::from(increment.to_le_bytes().to_vec());
+            assert!(!unsafe { batch.push(raft, command, sender) });
+            receivers.push(receiver);
+        }
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.bytes(), 24);
+
+        // The three commands are appended to the log by a single raft_apply.
+        unsafe { batch.submit(raft, captures) };
+        assert_eq!(batch.len(), 0);
+        assert_eq!(unsafe { raft_last_index(raft) }, last_index + 3);
+
+        assert!(fixture.step_until_applied(Some(0), last_index + 3, Duration::from_secs(2)));
+        let outputs: Vec<_> = receivers.into_iter().map(|r| r.now_or_never().unwrap().unwrap().unwrap()).collect();
+
+        // Each caller receives the output of its own command.
+        assert_eq!(outputs, vec![(1, last_index + 1), (3, last_index + 2), (6, last_index + 3)]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::future::Future;
+use std::mem;
+
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+use libc::{c_int, c_void};
+
+use crate::error::{RaftError, Result};
+use crate::state::Role;
+
+/// A membership change, as submitted to the raft library.
+pub(crate) enum Operation {
+    Add(u64, String),
+    Assign(u64, Role),
+    Remove(u64),
+}
+
+/// Serialises the membership changes, the raft library only accepts one at a time.
+#[derive(Default)]
+pub(crate) struct Changes {
+    queue: VecDeque<(Operation, oneshot::Sender<Result<()>>)>,
+    in_flight: bool,
+    closing: bool,
+}
+
+impl Changes {
+    /// Enqueues a change, it is submitted once the previous ones have completed.
+    ///
+    /// # Safety
+    ///
+    /// Both pointers must stay valid until every change has completed.
+    pub(crate) unsafe fn push(
+        this: *mut Changes,
+        raft: *mut raft,
+        operation: Operation,
+    ) -> impl Future<Output = Result<()>>
+    {
+        let (sender, receiver) = oneshot::channel();
+        Changes::submit(this, raft, operation, sender);
+
+        async move {
+            match receiver.await {
+                Ok(result) => result,
+                Err(oneshot::Canceled) => Err(RaftError::Canceled(None)),
+            }
+        }
+    }
+
+    /// Enqueues a change whose outcome is sent to the given sender.
+    ///
+    /// # Safety
+    ///
+    /// See `push`.
+    pub(crate) unsafe fn submit(
+        this: *mut Changes,
+        raft: *mut raft,
+        operation: Operation,
+        sender: oneshot::Sender<Result<()>>,
+    )
+    {
+        (*this).queue.push_back((operation, sender));
+        if !(*this).in_flight {
+            Changes::next(this, raft);
+        }
+    }
+
+    /// Cancels the queued changes, the raft instance is being closed.
+    pub(crate) fn close(&mut self) {
+        self.closing = true;
+        for (_, sender) in self.queue.drain(..) {
+            let _ = sender.send(Err(RaftError::Canceled(None)));
+        }
+    }
+
+    /// Submits the queued changes until one of them is accepted by the raft library.
+    unsafe fn next(this: *mut Changes, raft: *mut raft) {
+        while let Some((operation, sender)) = (*this).queue.pop_front() {
+            if (*this).closing {
+                let _ = sender.send(Err(RaftError::Canceled(None)));
+                continue;
+            }
+
+            let operation = match operation {
+                Operation::Add(id, address) => match CString::new(address) {
+                    Ok(address) => Raw::Add(id, address),
+                    Err(_) => {
+                        let _ = sender.send(Err(RaftError::Invalid(None)));
+                        continue;
+                    },
+                },
+                Operation::Assign(id, role) => Raw::Assign(id, role.to_raw()),
+                Operation::Remove(id) => Raw::Remove(id),
+            };
+
+            let req = Box::into_raw(Box::new(ChangeRequest { raw: mem::zeroed(), sender, changes: this, raft }));
+            (*req).raw.data = req as *mut c_void;
+
+            let raw = &mut (*req).raw;
+            let rv = match operation {
+                Raw::Add(id, address) => raft_add(raft, raw, id, address.as_ptr(), Some(change_cb)),
+                Raw::Assign(id, role) => raft_assign(raft, raw, id, role, Some(change_cb)),
+                Raw::Remove(id) => raft_remove(raft, raw, id, Some(change_cb)),
+            };
+
+            if rv == 0 {
+                (*this).in_flight = true;
+                return;
+            }
+
+            let req = Box::from_raw(req);
+            let _ = req.sender.send(Err(RaftError::from_raft(rv, raft)));
+        }
+    }
+}
+
+enum Raw {
+    Add(u64, CString),
+    Assign(u64, c_int),
+    Remove(u64),
+}
+
+struct ChangeRequest {
+    raw: raft_change,
+    sender: oneshot::Sender<Result<()>>,
+    changes: *mut Changes,
+    raft: *mut raft,
+}
+
+unsafe extern "C" fn change_cb(req: *mut raft_change, status: c_int) {
+    let req = Box::from_raw((*req).data as *mut ChangeRequest);
+    let ChangeRequest { sender, changes, raft, .. } = *req;
+
+    let result = if status == 0 { Ok(()) } else { Err(RaftError::from_code(status)) };
+    let _ = sender.send(result);
+
+    (*changes).in_flight = false;
+    Changes::next(changes, raft);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::{cluster, Counter};
+    use crate::testing::Fixture;
+
+    /// Steps the cluster until every change submitted to the queue has completed.
+    fn settle(fixture: &mut Fixture<Counter>, changes: *mut Changes) {
+        let settled = fixture.step_until(Duration::from_secs(5), |_| unsafe {
+            !(*changes).in_flight && (*changes).queue.is_empty()
+        });
+        assert!(settled);
+    }
+
+    #[test]
+    fn changes_wait_for_the_one_in_progress() {
+        let mut fixture = cluster();
+        fixture.grow(Counter(0)).unwrap();
+        fixture.grow(Counter(0)).unwrap();
+        let configuration_index = fixture.status(0).configuration_index;
+
+        let mut changes = Box::new(Changes::default());
+        let this: *mut Changes = &mut *changes;
+        let raft = fixture.raft(0);
+
+        // The raft library would reject the second one with CantChange if it was submitted now.
+        let first = unsafe { Changes::push(this, raft, Operation::Add(4, "4".to_owned())) };
+        let second = unsafe { Changes::push(this, raft, Operation::Add(5, "5".to_owned())) };
+        assert!(changes.in_flight);
+        assert_eq!(changes.queue.len(), 1);
+
+        settle(&mut fixture, this);
+        assert_eq!(first.now_or_never(), Some(Ok(())));
+        assert_eq!(second.now_or_never(), Some(Ok(())));
+        assert!(fixture.status(0).configuration_index > configuration_index);
+    }
+
+    #[test]
+    fn failures_are_reported_to_their_caller_only() {
+        let mut fixture = cluster();
+        fixture.grow(Counter(0)).unwrap();
+
+        let mut changes = Box::new(Changes::default());
+        let this: *mut Changes = &mut *changes;
+        let raft = fixture.raft(0);
+
+        let added = unsafe { Changes::push(this, raft, Operation::Add(4, "4".to_owned())) };
+        let duplicate = unsafe { Changes::push(this, raft, Operation::Add(1, "other".to_owned())) };
+        let invalid = unsafe { Changes::push(this, raft, Operation::Add(6, "nul\0".to_owned())) };
+        let removed = unsafe { Changes::push(this, raft, Operation::Remove(4)) };
+
+        settle(&mut fixture, this);
+        assert_eq!(added.now_or_never(), Some(Ok(())));
+        assert!(matches!(duplicate.now_or_never(), Some(Err(RaftError::DuplicateId(_)))));
+        assert!(matches!(invalid.now_or_never(), Some(Err(RaftError::Invalid(_)))));
+        assert_eq!(removed.now_or_never(), Some(Ok(())));
+
+        // A follower rejects the changes, naming the error of the raft library.
+        let mut follower = Changes::default();
+        let rejected = unsafe { Changes::push(&mut follower, fixture.raft(1), Operation::Remove(3)) };
+        assert!(matches!(rejected.now_or_never(), Some(Err(RaftError::NotLeader(_)))));
+
+        // The queued changes are canceled when the instance is closed.
+        let mut closing = Changes { in_flight: true, ..Changes::default() };
+        let canceled = unsafe { Changes::push(&mut closing, fixture.raft(0), Operation::Remove(4)) };
+        closing.close();
+        assert!(matches!(canceled.now_or_never(), Some(Err(RaftError::Canceled(_)))));
+    }
+}
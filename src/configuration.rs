@@ -0,0 +1,244 @@
+use std::convert::TryInto;
+use std::ffi::{CStr, CString};
+use std::{fmt, mem, ptr, slice};
+
+use canonical_raft_sys::*;
+use libc::c_int;
+
+use crate::error::{RaftError, Result};
+use crate::state::Role;
+
+/// The version of the configuration encoding format, as written by `raft_configuration_encode`.
+const ENCODING_FORMAT: u8 = 1;
+
+/// The servers that make up a cluster, along with their role.
+///
+/// ```ignore
+/// let configuration = Configuration::builder()
+///     .voter(1, "127.0.0.1:9001")
+///     .voter(2, "127.0.0.1:9002")
+///     .standby(3, "127.0.0.1:9003")
+///     .build()?;
+/// ```
+pub struct Configuration {
+    raw: raft_configuration,
+}
+
+impl Configuration {
+    /// Creates an empty configuration.
+    pub fn new() -> Configuration {
+        let mut raw = raft_configuration { servers: ptr::null_mut(), n: 0 };
+        unsafe { raft_configuration_init(&mut raw) };
+        Configuration { raw }
+    }
+
+    pub fn builder() -> ConfigurationBuilder {
+        ConfigurationBuilder { servers: Vec::new() }
+    }
+
+    /// Adds a server to this configuration.
+    ///
+    /// It fails with `RaftError::DuplicateId` or `RaftError::DuplicateAddress`
+    /// if another server already has the same id or address.
+    pub fn add(&mut self, id: u64, address: &str, role: Role) -> Result<()> {
+        let address = CString::new(address).map_err(|_| RaftError::Invalid(None))?;
+        let rv = unsafe { raft_configuration_add(&mut self.raw, id, address.as_ptr(), role.to_raw()) };
+        if rv != 0 {
+            return Err(RaftError::from_code(rv));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the server with the given id, if any.
+    pub fn get(&self, id: u64) -> Option<Server<'_>> {
+        self.iter().find(|server| server.id == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.raw.n as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.n == 0
+    }
+
+    /// Iterates over the servers, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = Server<'_>> {
+        self.servers().iter().map(|server| unsafe { Server::from_raw(server) })
+    }
+
+    fn servers(&self) -> &[raft_server] {
+        if self.raw.servers.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.raw.servers, self.raw.n as usize) }
+    }
+
+    /// Encodes this configuration in the format of `raft_configuration_encode`.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        unsafe { encode(&self.raw) }
+    }
+
+    /// Decodes a configuration previously encoded by `encode`.
+    pub fn decode(bytes: &[u8]) -> Result<Configuration> {
+        let mut configuration = Configuration::new();
+        let mut cursor = bytes;
+
+        if take(&mut cursor, 1)?[0] != ENCODING_FORMAT {
+            return Err(RaftError::Malformed(None));
+        }
+
+        let n = u64::from_le_bytes(take(&mut cursor, 8)?.try_into().unwrap());
+        for _ in 0..n {
+            let id = u64::from_le_bytes(take(&mut cursor, 8)?.try_into().unwrap());
+            let nul = cursor.iter().position(|b| *b == 0).ok_or(RaftError::Malformed(None))?;
+            let address = std::str::from_utf8(&cursor[..nul]).map_err(|_| RaftError::Malformed(None))?;
+            cursor = &cursor[nul + 1..];
+            let role = Role::from_raw(take(&mut cursor, 1)?[0] as c_int)?;
+            configuration.add(id, address, role)?;
+        }
+
+        Ok(configuration)
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const raft_configuration {
+        &self.raw
+    }
+
+    pub(crate) fn as_raw(&mut self) -> *mut raft_configuration {
+        &mut self.raw
+    }
+
+    /// Gives up the ownership of the servers, that must then be released by the raft library.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn into_raw(self) -> raft_configuration {
+        let configuration = self.raw;
+        mem::forget(self);
+        configuration
+    }
+}
+
+impl Default for Configuration {
+    fn default() -> Configuration {
+        Configuration::new()
+    }
+}
+
+impl fmt::Debug for Configuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Drop for Configuration {
+    fn drop(&mut self) {
+        unsafe { raft_configuration_close(&mut self.raw) }
+    }
+}
+
+/// A server of a `Configuration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Server<'a> {
+    pub id: u64,
+    pub address: &'a str,
+    pub role: Role,
+}
+
+impl<'a> Server<'a> {
+    unsafe fn from_raw(server: &'a raft_server) -> Server<'a> {
+        // The addresses and roles were validated when added to the configuration.
+        let address = CStr::from_ptr(server.address).to_str().unwrap_or_default();
+        let role = Role::from_raw(server.role).unwrap_or(Role::Spare);
+        Server { id: server.id, address, role }
+    }
+}
+
+/// Builds a `Configuration`, the servers are validated by `build`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigurationBuilder {
+    servers: Vec<(u64, String, Role)>,
+}
+
+impl ConfigurationBuilder {
+    pub fn voter(self, id: u64, address: impl Into<String>) -> ConfigurationBuilder {
+        self.server(id, address, Role::Voter)
+    }
+
+    pub fn standby(self, id: u64, address: impl Into<String>) -> ConfigurationBuilder {
+        self.server(id, address, Role::Standby)
+    }
+
+    pub fn spare(self, id: u64, address: impl Into<String>) -> ConfigurationBuilder {
+        self.server(id, address, Role::Spare)
+    }
+
+    pub fn server(mut self, id: u64, address: impl Into<String>, role: Role) -> ConfigurationBuilder {
+        self.servers.push((id, address.into(), role));
+        self
+    }
+
+    /// Builds the configuration, it fails with `RaftError::DuplicateId`
+    /// or `RaftError::DuplicateAddress` if two servers are conflicting.
+    pub fn build(self) -> Result<Configuration> {
+        let mut configuration = Configuration::new();
+        for (id, address, role) in self.servers {
+            configuration.add(id, &address, role)?;
+        }
+        Ok(configuration)
+    }
+}
+
+/// Encodes a configuration that is owned by the raft library.
+pub(crate) unsafe fn encode(configuration: *const raft_configuration) -> Result<Vec<u8>> {
+    let mut buf = raft_buffer { base: ptr::null_mut(), len: 0 };
+    let rv = raft_configuration_encode(configuration, &mut buf);
+    if rv != 0 {
+        return Err(RaftError::from_code(rv));
+    }
+
+    let bytes = slice::from_raw_parts(buf.base as *const u8, buf.len).to_vec();
+    raft_free(buf.base);
+
+    Ok(bytes)
+}
+
+fn take<'a>(cursor: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if cursor.len() < n {
+        return Err(RaftError::Malformed(None));
+    }
+    let (head, tail) = cursor.split_at(n);
+    *cursor = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let result = Configuration::builder().voter(1, "a").standby(1, "b").build();
+        assert!(matches!(result, Err(RaftError::DuplicateId(_))));
+
+        let result = Configuration::builder().voter(1, "a").spare(2, "a").build();
+        assert!(matches!(result, Err(RaftError::DuplicateAddress(_))));
+    }
+
+    #[test]
+    fn encode_decode() {
+        let configuration = Configuration::builder()
+            .voter(1, "127.0.0.1:9001")
+            .standby(2, "127.0.0.1:9002")
+            .spare(3, "127.0.0.1:9003")
+            .build()
+            .unwrap();
+
+        let bytes = configuration.encode().unwrap();
+        let decoded = Configuration::decode(&bytes).unwrap();
+
+        let servers: Vec<_> = decoded.iter().collect();
+        assert_eq!(servers, configuration.iter().collect::<Vec<_>>());
+        assert_eq!(decoded.get(2).map(|s| s.role), Some(Role::Standby));
+    }
+}
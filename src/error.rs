@@ -0,0 +1,177 @@
+use std::ffi::CStr;
+use std::{error, fmt, io, result};
+
+use canonical_raft_sys::*;
+use libc::{c_char, c_int};
+
+macro_rules! raft_errors {
+    ($($(#[$doc:meta])* $variant:ident => $code:ident,)*) => {
+        /// An error returned by the raft library, along with the message
+        /// describing it that was captured at the failure site, if any.
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum RaftError {
+            $($(#[$doc])* $variant(Option<String>),)*
+            /// The operation did not complete in time, the raft library has no code
+            /// for it and reports it as `RAFT_BUSY`.
+            Timeout(Option<String>),
+            /// An error code unknown to this version of the bindings.
+            Other(c_int, Option<String>),
+        }
+
+        impl RaftError {
+            /// Converts a raw error code into a `RaftError`, without any specific message.
+            pub fn from_code(code: c_int) -> RaftError {
+                RaftError::with_message(code, None)
+            }
+
+            /// Converts a raw error code into a `RaftError` described by the given message.
+            pub fn with_message(code: c_int, message: Option<String>) -> RaftError {
+                match code {
+                    $(c if c == $code as c_int => RaftError::$variant(message),)*
+                    other => RaftError::Other(other, message),
+                }
+            }
+
+            /// Returns the raw error code understood by the raft library.
+            pub fn code(&self) -> c_int {
+                match self {
+                    $(RaftError::$variant(_) => $code as c_int,)*
+                    RaftError::Timeout(_) => RAFT_BUSY as c_int,
+                    RaftError::Other(code, _) => *code,
+                }
+            }
+
+            /// Returns the message captured at the failure site, if any.
+            pub fn message(&self) -> Option<&str> {
+                match self {
+                    $(RaftError::$variant(message))|*
+                    | RaftError::Timeout(message)
+                    | RaftError::Other(_, message) => {
+                        message.as_deref()
+                    },
+                }
+            }
+        }
+    };
+}
+
+raft_errors! {
+    /// Out of memory.
+    NoMem => RAFT_NOMEM,
+    /// Server ID is not valid.
+    BadId => RAFT_BADID,
+    /// Server ID already in use.
+    DuplicateId => RAFT_DUPLICATEID,
+    /// Server address already in use.
+    DuplicateAddress => RAFT_DUPLICATEADDRESS,
+    /// Server role is not valid.
+    BadRole => RAFT_BADROLE,
+    /// Encoded data is malformed.
+    Malformed => RAFT_MALFORMED,
+    /// Server is not the leader.
+    NotLeader => RAFT_NOTLEADER,
+    /// Server has lost leadership.
+    LeadershipLost => RAFT_LEADERSHIPLOST,
+    /// Server is shutting down.
+    Shutdown => RAFT_SHUTDOWN,
+    /// Bootstrap only works on new clusters.
+    CantBootstrap => RAFT_CANTBOOTSTRAP,
+    /// A configuration change is already in progress.
+    CantChange => RAFT_CANTCHANGE,
+    /// Persisted data is corrupted.
+    Corrupt => RAFT_CORRUPT,
+    /// Operation was canceled.
+    Canceled => RAFT_CANCELED,
+    /// Resource name too long.
+    NameTooLong => RAFT_NAMETOOLONG,
+    /// Data is too big.
+    TooBig => RAFT_TOOBIG,
+    /// No connection to remote server available.
+    NoConnection => RAFT_NOCONNECTION,
+    /// Operation can't be performed at this time.
+    Busy => RAFT_BUSY,
+    /// I/O error.
+    IoErr => RAFT_IOERR,
+    /// Resource not found.
+    NotFound => RAFT_NOTFOUND,
+    /// Invalid parameter.
+    Invalid => RAFT_INVALID,
+    /// No access to resource.
+    Unauthorized => RAFT_UNAUTHORIZED,
+    /// Not enough disk space.
+    NoSpace => RAFT_NOSPACE,
+    /// System or raft limit met or exceeded.
+    TooMany => RAFT_TOOMANY,
+}
+
+impl RaftError {
+    /// Converts an error code returned by a function of the given raft instance,
+    /// capturing the message that the instance recorded.
+    pub(crate) unsafe fn from_raft(code: c_int, raft: *mut raft) -> RaftError {
+        RaftError::with_message(code, errmsg(raft_errmsg(raft)))
+    }
+
+    /// Converts an error code returned along with an `errmsg` buffer,
+    /// like the ones of the `raft_io` and `raft_uv_transport` structs.
+    pub(crate) fn from_errmsg(code: c_int, errmsg_buf: &[c_char]) -> RaftError {
+        RaftError::with_message(code, unsafe { errmsg(errmsg_buf.as_ptr()) })
+    }
+}
+
+unsafe fn errmsg(message: *const c_char) -> Option<String> {
+    if message.is_null() {
+        return None;
+    }
+
+    match CStr::from_ptr(message).to_string_lossy() {
+        message if message.is_empty() => None,
+        message => Some(message.into_owned()),
+    }
+}
+
+impl fmt::Display for RaftError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message() {
+            Some(message) => f.write_str(message),
+            None if matches!(self, RaftError::Timeout(_)) => f.write_str("operation timed out"),
+            None => {
+                // This is safe since the error messages returned from raft_strerror are static.
+                let message = unsafe { CStr::from_ptr(raft_strerror(self.code())) };
+                f.write_str(&message.to_string_lossy())
+            },
+        }
+    }
+}
+
+impl error::Error for RaftError {}
+
+/// The errors of the readers and writers given to `Fsm::snapshot` and `Fsm::restore`.
+impl From<io::Error> for RaftError {
+    fn from(error: io::Error) -> RaftError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => RaftError::Malformed(Some(error.to_string())),
+            _ => RaftError::IoErr(Some(error.to_string())),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, RaftError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for code in 1..=RAFT_TOOMANY as c_int {
+            let error = RaftError::from_code(code);
+            assert!(!matches!(error, RaftError::Other(..)), "code {} is not mapped", code);
+            assert_eq!(error.code(), code);
+        }
+
+        assert_eq!(RaftError::from_code(42), RaftError::Other(42, None));
+        assert_eq!(RaftError::from_code(RAFT_NOTLEADER as c_int), RaftError::NotLeader(None));
+        assert_eq!(RaftError::Timeout(None).code(), RAFT_BUSY as c_int);
+        assert_eq!(RaftError::Timeout(None).to_string(), "operation timed out");
+    }
+}
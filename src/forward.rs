@@ -0,0 +1,101 @@
+use bytes::Bytes;
+use futures_channel::oneshot;
+use futures_util::future::LocalBoxFuture;
+
+use crate::error::{RaftError, Result};
+
+/// The number of times a request is sent before giving up, the leader changing in between.
+pub(crate) const MAX_ATTEMPTS: usize = 5;
+
+/// Sends the requests of a follower to the leader, see `Raft::set_forward`.
+///
+/// The raft wire protocol has no room for application messages, the requests are
+/// therefore sent over your own RPC layer. On the leader they must be given to
+/// `Raft::apply` and `Raft::linearizable_read` respectively, and the results sent back.
+pub trait Forward<T> {
+    /// Sends the command to the given leader, which applies it and returns its result.
+    ///
+    /// Only `RaftError::NotLeader` makes the command be sent again, the leader must therefore
+    /// return it as is and the transport must not report its own failures with it: when the
+    /// outcome is unknown the command may have been applied, sending it again would apply it twice.
+    fn apply(&self, id: u64, address: &str, command: Bytes) -> LocalBoxFuture<'static, Result<(T, u64)>>;
+
+    /// Asks the given leader for the index up to which a read must wait.
+    fn read_index(&self, id: u64, address: &str) -> LocalBoxFuture<'static, Result<u64>>;
+}
+
+/// Returns `true` if the command was not appended to any log and can be sent again.
+///
+/// A command whose leader lost the leadership may still be committed by the next one.
+pub(crate) fn can_resend_apply(error: &RaftError) -> bool {
+    matches!(error, RaftError::NotLeader(_))
+}
+
+/// Returns `true` if the read can be sent again, possibly to another leader, reads have no effect.
+pub(crate) fn can_resend_read(error: &RaftError) -> bool {
+    matches!(error, RaftError::NotLeader(_) | RaftError::LeadershipLost(_) | RaftError::NoConnection(_))
+}
+
+/// Wakes the futures waiting for a counter of the raft instance to reach a value,
+/// such as the index of the last applied entry or the time.
+#[derive(Default)]
+pub(crate) struct Waiters {
+    waiters: Vec<(u64, oneshot::Sender<()>)>,
+}
+
+impl Waiters {
+    pub(crate) fn wait(&mut self, target: u64, current: u64) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        if target <= current {
+            let _ = sender.send(());
+        } else {
+            self.waiters.push((target, sender));
+        }
+        receiver
+    }
+
+    pub(crate) fn observe(&mut self, current: u64) {
+        let mut i = 0;
+        while i < self.waiters.len() {
+            if self.waiters[i].0 <= current {
+                let (_, sender) = self.waiters.swap_remove(i);
+                let _ = sender.send(());
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn only_commands_that_reached_no_log_are_sent_again() {
+        assert!(can_resend_apply(&RaftError::NotLeader(None)));
+        assert!(!can_resend_apply(&RaftError::LeadershipLost(None)));
+        assert!(!can_resend_apply(&RaftError::NoConnection(None)));
+
+        assert!(can_resend_read(&RaftError::LeadershipLost(None)));
+        assert!(can_resend_read(&RaftError::NoConnection(None)));
+        assert!(!can_resend_read(&RaftError::Busy(None)));
+    }
+
+    #[test]
+    fn waiters_are_woken_once_the_target_is_reached() {
+        let mut waiters = Waiters::default();
+        assert_eq!(waiters.wait(3, 5).now_or_never(), Some(Ok(())));
+
+        let mut first = waiters.wait(7, 5);
+        let mut second = waiters.wait(9, 5);
+        waiters.observe(8);
+        assert_eq!((&mut first).now_or_never(), Some(Ok(())));
+        assert_eq!((&mut second).now_or_never(), None);
+
+        waiters.observe(9);
+        assert_eq!(second.now_or_never(), Some(Ok(())));
+    }
+}
@@ -0,0 +1,269 @@
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::{mem, ptr, slice};
+
+use canonical_raft_sys::*;
+use libc::{c_int, c_uint, c_void};
+
+use crate::batch::Captures;
+use crate::error::{RaftError, Result};
+
+/// The replicated finite state machine, the only thing you have to implement.
+///
+/// This is the same interface as [the HashiCorp one](https://pkg.go.dev/github.com/hashicorp/raft?tab=doc#FSM),
+/// commands are applied in the same order on every server of the cluster.
+pub trait Fsm {
+    /// The value returned by `apply`, it is handed to the caller that submitted the command.
+    type Output;
+
+    /// Applies a committed command to the state machine.
+    fn apply(&mut self, command: &[u8]) -> Result<Self::Output>;
+
+    /// Serializes the whole state of the state machine into the given writer.
+    ///
+    /// The snapshot is spooled in chunks of `SNAPSHOT_CHUNK` bytes as it is
+    /// written, it never has to be materialized as a single buffer.
+    fn snapshot<W: Write>(&self, writer: W) -> Result<()>;
+
+    /// Replaces the whole state of the state machine by the snapshot read
+    /// from the given reader, previously written by `snapshot`.
+    fn restore<R: Read>(&mut self, reader: R) -> Result<()>;
+}
+
+/// The maximum size of the buffers a snapshot is spooled into.
+pub const SNAPSHOT_CHUNK: usize = 1024 * 1024;
+
+/// The size of the first buffer of a snapshot, it doubles up to `SNAPSHOT_CHUNK`.
+const FIRST_CHUNK: usize = 4096;
+
+/// Exposes any `Fsm` as a `raft_fsm` that can be given to the raft library.
+///
+/// The adapter takes care of the memory allocated by the raft library and
+/// never lets a panic unwind across the FFI boundary.
+pub struct FsmAdapter<F: Fsm> {
+    inner: Box<Inner<F>>,
+}
+
+struct Inner<F: Fsm> {
+    raw: raft_fsm,
+    fsm: F,
+    // The result of the last applied command, the `result` pointer given to
+    // the raft library points to this slot. The apply callback takes it out.
+    output: Option<F::Output>,
+    // The entries whose output is sent directly to the caller that submitted them.
+    captures: Captures<F::Output>,
+}
+
+impl<F: Fsm> FsmAdapter<F> {
+    pub fn new(fsm: F) -> FsmAdapter<F> {
+        let raw = raft_fsm {
+            version: 1,
+            data: ptr::null_mut(),
+            apply: Some(fsm_apply::<F>),
+            snapshot: Some(fsm_snapshot::<F>),
+            restore: Some(fsm_restore::<F>),
+        };
+
+        // The inner struct is boxed, the data pointer stays valid when the adapter moves.
+        let mut inner = Box::new(Inner { raw, fsm, output: None, captures: Captures::default() });
+        inner.raw.data = &mut *inner as *mut Inner<F> as *mut c_void;
+
+        FsmAdapter { inner }
+    }
+
+    /// Returns the `raft_fsm` to give to `raft_init`, it is valid as long as the adapter lives.
+    pub fn as_raw(&mut self) -> *mut raft_fsm {
+        &mut self.inner.raw
+    }
+
+    pub fn fsm(&self) -> &F {
+        &self.inner.fsm
+    }
+
+    pub fn fsm_mut(&mut self) -> &mut F {
+        &mut self.inner.fsm
+    }
+
+    pub(crate) fn captures(&mut self) -> *mut Captures<F::Output> {
+        &mut self.inner.captures
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner.fsm
+    }
+}
+
+/// Borrows the bytes of a buffer in place, the buffer must outlive the returned slice.
+pub(crate) unsafe fn slice_from_buf<'a>(buf: &raft_buffer) -> &'a [u8] {
+    if buf.len == 0 {
+        return &[];
+    }
+    slice::from_raw_parts(buf.base as *const u8, buf.len)
+}
+
+/// Runs one of the `Fsm` methods, converting a panic into an error,
+/// unwinding across the FFI boundary is undefined behavior.
+fn catch_panic<T, G: FnOnce() -> Result<T>>(f: G) -> Result<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => Err(RaftError::IoErr(Some("the state machine panicked".to_string()))),
+    }
+}
+
+unsafe extern "C" fn fsm_apply<F: Fsm>(
+    fsm: *mut raft_fsm,
+    buf: *const raft_buffer,
+    result: *mut *mut c_void,
+) -> c_int
+{
+    let inner = &mut *((*fsm).data as *mut Inner<F>);
+
+    // The buffer is owned by the raft log, we must not free it.
+    let command = slice_from_buf(&*buf);
+
+    match catch_panic(|| inner.fsm.apply(command)) {
+        Ok(output) => {
+            inner.output = inner.captures.capture(output);
+            *result = &mut inner.output as *mut Option<F::Output> as *mut c_void;
+            0
+        },
+        Err(error) => error.code(),
+    }
+}
+
+/// Spools a snapshot into buffers allocated with raft_malloc, the raft library
+/// releases them with raft_free once the snapshot is persisted.
+struct ChunkWriter {
+    bufs: Vec<raft_buffer>,
+    // The allocated size of the last buffer.
+    capacity: usize,
+    // Set when an allocation failed, the writer error is then reported as `NoMem`.
+    out_of_memory: bool,
+}
+
+impl ChunkWriter {
+    fn new() -> ChunkWriter {
+        ChunkWriter { bufs: Vec::new(), capacity: 0, out_of_memory: false }
+    }
+
+    /// Makes room in the last buffer, growing it or starting a new one.
+    unsafe fn reserve(&mut self) -> io::Result<()> {
+        match self.bufs.last_mut() {
+            // The first buffer grows until it reaches the size of a chunk,
+            // small snapshots do not have to allocate a whole chunk.
+            Some(buf) if self.capacity < SNAPSHOT_CHUNK => {
+                let capacity = (self.capacity * 2).min(SNAPSHOT_CHUNK);
+                let base = raft_realloc(buf.base, capacity);
+                if base.is_null() {
+                    self.out_of_memory = true;
+                    return Err(out_of_memory());
+                }
+                buf.base = base;
+                self.capacity = capacity;
+            },
+            last => {
+                let capacity = if last.is_none() { FIRST_CHUNK } else { SNAPSHOT_CHUNK };
+                let base = raft_malloc(capacity);
+                if base.is_null() {
+                    self.out_of_memory = true;
+                    return Err(out_of_memory());
+                }
+                self.bufs.push(raft_buffer { base, len: 0 });
+                self.capacity = capacity;
+            },
+        }
+
+        Ok(())
+    }
+
+    fn into_bufs(mut self) -> Vec<raft_buffer> {
+        mem::take(&mut self.bufs)
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        unsafe {
+            if !matches!(self.bufs.last(), Some(buf) if buf.len < self.capacity) {
+                self.reserve()?;
+            }
+
+            let buf = self.bufs.last_mut().unwrap();
+            let n = data.len().min(self.capacity - buf.len);
+            ptr::copy_nonoverlapping(data.as_ptr(), (buf.base as *mut u8).add(buf.len), n);
+            buf.len += n;
+
+            Ok(n)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        for buf in &self.bufs {
+            unsafe { raft_free(buf.base) }
+        }
+    }
+}
+
+/// The error itself is not reported, the writer records that it ran out of memory.
+fn out_of_memory() -> io::Error {
+    io::Error::from(io::ErrorKind::Other)
+}
+
+unsafe extern "C" fn fsm_snapshot<F: Fsm>(
+    fsm: *mut raft_fsm,
+    bufs: *mut *mut raft_buffer,
+    n_bufs: *mut c_uint,
+) -> c_int
+{
+    let inner = &*((*fsm).data as *const Inner<F>);
+
+    let mut writer = ChunkWriter::new();
+    if let Err(error) = catch_panic(|| inner.fsm.snapshot(&mut writer)) {
+        return if writer.out_of_memory { RAFT_NOMEM } else { error.code() };
+    }
+
+    // The raft library expects at least one buffer, even for an empty snapshot.
+    if writer.bufs.is_empty() && writer.reserve().is_err() {
+        return RAFT_NOMEM;
+    }
+
+    let chunks = writer.into_bufs();
+    let array = raft_malloc(chunks.len() * mem::size_of::<raft_buffer>()) as *mut raft_buffer;
+    if array.is_null() {
+        chunks.iter().for_each(|buf| raft_free(buf.base)); // avoid leaking!
+        return RAFT_NOMEM;
+    }
+    ptr::copy_nonoverlapping(chunks.as_ptr(), array, chunks.len());
+
+    *bufs = array;
+    *n_bufs = chunks.len() as c_uint;
+
+    0
+}
+
+unsafe extern "C" fn fsm_restore<F: Fsm>(fsm: *mut raft_fsm, buf: *mut raft_buffer) -> c_int {
+    let inner = &mut *((*fsm).data as *mut Inner<F>);
+
+    // The raft library always hands the snapshot to restore as a single buffer,
+    // it is read in place without any copy.
+    let snapshot = slice_from_buf(&*buf);
+
+    match catch_panic(|| inner.fsm.restore(snapshot)) {
+        Ok(()) => {
+            // The buffer is ours to release once the restore succeeded.
+            raft_free((*buf).base);
+            0
+        },
+        Err(error) => error.code(),
+    }
+}
@@ -0,0 +1,401 @@
+//! Routes the allocations of the raft library through a Rust allocator.
+//!
+//! By default the raft library uses the `malloc` of the C library, installing a heap
+//! makes it share the allocator of the rest of the program, e.g. jemalloc or mimalloc,
+//! and lets heap profilers see its memory.
+//!
+//! The raft library has a single heap for the whole process, it is shared by every
+//! raft instance and can only be installed once.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::{mem, ptr};
+
+use canonical_raft_sys::*;
+use libc::c_void;
+
+use crate::error::{RaftError, Result};
+
+/// The alignment of the memory returned by `malloc`, enough for any C type.
+const MIN_ALIGN: usize = 16;
+
+/// Every allocation is preceded by a header storing its size and alignment,
+/// the C interface does not give them back when the memory is released.
+const HEADER_SIZE: usize = 2 * mem::size_of::<usize>();
+
+/// Set while a heap is installed, until it is uninstalled.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The allocator used by the Rust program, as registered with `#[global_allocator]`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Global;
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        std::alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        std::alloc::dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        std::alloc::alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        std::alloc::realloc(ptr, layout, new_size)
+    }
+}
+
+/// An allocator that counts the memory allocated through it and can enforce a limit.
+///
+/// Once the limit is reached allocations return a null pointer, which the raft library
+/// reports as a `NoMem` error instead of the process being killed.
+///
+/// The heap of the raft library is process-wide, the counts and the limit therefore
+/// cover every raft instance of the process together, not a single one.
+///
+/// ```ignore
+/// static HEAP: ProcessCountingAlloc<Global> = ProcessCountingAlloc::new(Global);
+///
+/// HEAP.set_limit(Some(512 * 1024 * 1024));
+/// unsafe { canonical_raft::heap::install(&HEAP).unwrap() };
+/// ```
+#[derive(Debug)]
+pub struct ProcessCountingAlloc<A> {
+    allocator: A,
+    limit: AtomicUsize,
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+/// A snapshot of the statistics of a `ProcessCountingAlloc`, summed over every raft instance of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcessStats {
+    /// The number of bytes currently allocated.
+    pub live: usize,
+    /// The highest number of bytes that were allocated at once.
+    pub peak: usize,
+    /// The number of allocations made so far, reallocations included.
+    pub allocations: usize,
+    /// The number of allocations that were refused because of the limit.
+    pub failures: usize,
+}
+
+impl<A> ProcessCountingAlloc<A> {
+    /// Wraps the given allocator, without any limit.
+    pub const fn new(allocator: A) -> ProcessCountingAlloc<A> {
+        ProcessCountingAlloc {
+            allocator,
+            limit: AtomicUsize::new(usize::MAX),
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the maximum number of bytes that can be allocated at once, `None` removes the limit.
+    ///
+    /// The limit is shared by every raft instance of the process, a single instance
+    /// can exhaust it and make the allocations of the others fail.
+    ///
+    /// Lowering the limit under the live bytes does not release anything,
+    /// it only makes the next allocations fail.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of bytes that can be allocated at once, if any.
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Returns the number of bytes currently allocated by the whole process.
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current statistics of this allocator, there is no per-instance breakdown.
+    pub fn stats(&self) -> ProcessStats {
+        ProcessStats {
+            live: self.live.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the peak to the live bytes along with the counters.
+    pub fn reset_stats(&self) {
+        self.peak.store(self.live(), Ordering::Relaxed);
+        self.allocations.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Accounts for `size` more bytes, returns `false` if it would exceed the limit.
+    fn reserve(&self, size: usize) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut live = self.live.load(Ordering::Relaxed);
+        loop {
+            let new = match live.checked_add(size) {
+                Some(new) if new <= limit => new,
+                _ => {
+                    self.failures.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                },
+            };
+            match self.live.compare_exchange_weak(live, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    self.peak.fetch_max(new, Ordering::Relaxed);
+                    self.allocations.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                },
+                Err(current) => live = current,
+            }
+        }
+    }
+
+    fn release(&self, size: usize) {
+        self.live.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ProcessCountingAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.reserve(layout.size()) {
+            return ptr::null_mut();
+        }
+        let ptr = self.allocator.alloc(layout);
+        if ptr.is_null() {
+            self.release(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.allocator.dealloc(ptr, layout);
+        self.release(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if !self.reserve(layout.size()) {
+            return ptr::null_mut();
+        }
+        let ptr = self.allocator.alloc_zeroed(layout);
+        if ptr.is_null() {
+            self.release(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Only the growth is checked against the limit, shrinking always succeeds.
+        let grow = new_size.saturating_sub(layout.size());
+        if !self.reserve(grow) {
+            return ptr::null_mut();
+        }
+
+        let new = self.allocator.realloc(ptr, layout, new_size);
+        if new.is_null() {
+            self.release(grow);
+        } else {
+            self.release(layout.size().saturating_sub(new_size));
+        }
+        new
+    }
+}
+
+/// Makes the raft library allocate through the global allocator of the program.
+///
+/// # Safety
+///
+/// See `install`.
+pub unsafe fn install_global() -> Result<()> {
+    install(&Global)
+}
+
+/// Makes the raft library of the whole process allocate through the given allocator.
+///
+/// It fails with `RaftError::Invalid` if a heap is already installed, it must be
+/// uninstalled first. The `raft_heap` given to the raft library is leaked.
+///
+/// # Safety
+///
+/// This must be done before any raft object is created, the memory allocated by the
+/// previous heap would otherwise be released with this one.
+pub unsafe fn install<A: GlobalAlloc + Sync>(allocator: &'static A) -> Result<()> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Err(RaftError::Invalid(Some("a heap is already installed".to_owned())));
+    }
+
+    let heap = Box::new(raft_heap {
+        data: allocator as *const A as *mut c_void,
+        malloc: Some(heap_malloc::<A>),
+        free: Some(heap_free::<A>),
+        calloc: Some(heap_calloc::<A>),
+        realloc: Some(heap_realloc::<A>),
+        aligned_alloc: Some(heap_aligned_alloc::<A>),
+    });
+
+    raft_heap_set(Box::leak(heap));
+
+    Ok(())
+}
+
+/// Makes the raft library use the `malloc` of the C library again.
+///
+/// # Safety
+///
+/// No memory allocated by the raft library must be alive, it would
+/// otherwise be released with another allocator.
+pub unsafe fn uninstall() {
+    raft_heap_set_default();
+    INSTALLED.store(false, Ordering::SeqCst);
+}
+
+/// Returns the layout of an allocation of `size` bytes including its header,
+/// along with the offset of the memory given to the raft library.
+fn layout(size: usize, align: usize) -> Option<(Layout, usize)> {
+    let align = align.max(MIN_ALIGN);
+    // Both are powers of two, the offset is a multiple of the alignment that leaves room for the header.
+    let offset = align.max(HEADER_SIZE);
+    let size = size.checked_add(offset)?;
+    Layout::from_size_align(size, align).ok().map(|layout| (layout, offset))
+}
+
+/// Writes the header in front of the memory given to the raft library and returns this memory.
+unsafe fn finish(base: *mut u8, offset: usize, size: usize, align: usize) -> *mut c_void {
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+
+    let ptr = base.add(offset);
+    let header = ptr.sub(HEADER_SIZE) as *mut usize;
+    header.write(size);
+    header.add(1).write(align);
+
+    ptr as *mut c_void
+}
+
+/// Reads the header of an allocation, returns its base pointer, layout and size.
+unsafe fn header(ptr: *mut c_void) -> (*mut u8, Layout, usize) {
+    let ptr = ptr as *mut u8;
+    let header = ptr.sub(HEADER_SIZE) as *const usize;
+    let size = header.read();
+    let align = header.add(1).read();
+
+    let (layout, offset) = layout(size, align).expect("the layout was valid when allocated");
+    (ptr.sub(offset), layout, size)
+}
+
+unsafe fn allocate<A: GlobalAlloc>(data: *mut c_void, size: usize, align: usize, zeroed: bool) -> *mut c_void {
+    let allocator = &*(data as *const A);
+    let (layout, offset) = match layout(size, align) {
+        Some(layout) => layout,
+        None => return ptr::null_mut(),
+    };
+
+    let base = if zeroed { allocator.alloc_zeroed(layout) } else { allocator.alloc(layout) };
+    finish(base, offset, size, align)
+}
+
+unsafe extern "C" fn heap_malloc<A: GlobalAlloc>(data: *mut c_void, size: usize) -> *mut c_void {
+    allocate::<A>(data, size, MIN_ALIGN, false)
+}
+
+unsafe extern "C" fn heap_free<A: GlobalAlloc>(data: *mut c_void, ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let allocator = &*(data as *const A);
+    let (base, layout, _) = header(ptr);
+    allocator.dealloc(base, layout);
+}
+
+unsafe extern "C" fn heap_calloc<A: GlobalAlloc>(data: *mut c_void, nmemb: usize, size: usize) -> *mut c_void {
+    match nmemb.checked_mul(size) {
+        Some(size) => allocate::<A>(data, size, MIN_ALIGN, true),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn heap_realloc<A: GlobalAlloc>(data: *mut c_void, ptr: *mut c_void, size: usize) -> *mut c_void {
+    if ptr.is_null() {
+        return heap_malloc::<A>(data, size);
+    }
+
+    let allocator = &*(data as *const A);
+    let (base, old_layout, _) = header(ptr);
+    let align = old_layout.align();
+    let (new_layout, offset) = match layout(size, align) {
+        Some(layout) => layout,
+        None => return ptr::null_mut(),
+    };
+
+    // The offset only depends on the alignment, the header stays in place.
+    let base = allocator.realloc(base, old_layout, new_layout.size());
+    finish(base, offset, size, align)
+}
+
+unsafe extern "C" fn heap_aligned_alloc<A: GlobalAlloc>(data: *mut c_void, alignment: usize, size: usize) -> *mut c_void {
+    if !alignment.is_power_of_two() {
+        return ptr::null_mut();
+    }
+    allocate::<A>(data, size, alignment, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_keep_their_header() {
+        let data = &Global as *const Global as *mut c_void;
+        unsafe {
+            let ptr = heap_aligned_alloc::<Global>(data, 64, 100);
+            assert_eq!(ptr as usize % 64, 0);
+            ptr::write_bytes(ptr as *mut u8, 42, 100);
+
+            let ptr = heap_realloc::<Global>(data, ptr, 1000);
+            assert_eq!(ptr as usize % 64, 0);
+            assert_eq!(*(ptr as *mut u8).add(99), 42);
+            assert_eq!(header(ptr).2, 1000);
+            heap_free::<Global>(data, ptr);
+
+            let ptr = heap_calloc::<Global>(data, 10, 10) as *mut u8;
+            assert!((0..100).all(|i| *ptr.add(i) == 0));
+            heap_free::<Global>(data, ptr as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn counting_allocations_respect_the_limit() {
+        let heap = ProcessCountingAlloc::new(Global);
+        heap.set_limit(Some(4096));
+        let data = &heap as *const ProcessCountingAlloc<Global> as *mut c_void;
+        unsafe {
+            let ptr = heap_malloc::<ProcessCountingAlloc<Global>>(data, 1000);
+            assert!(!ptr.is_null());
+            assert!(heap.live() >= 1000);
+
+            let big = heap_malloc::<ProcessCountingAlloc<Global>>(data, 4096);
+            assert!(big.is_null());
+
+            heap_free::<ProcessCountingAlloc<Global>>(data, ptr);
+        }
+
+        let stats = heap.stats();
+        assert_eq!(stats.live, 0);
+        assert!(stats.peak >= 1000);
+        assert_eq!(stats.allocations, 1);
+        assert_eq!(stats.failures, 1);
+    }
+}
@@ -0,0 +1,23 @@
+use canonical_raft_sys::raft_io;
+
+mod transport;
+mod uv;
+#[cfg(feature = "tokio")]
+mod tokio;
+
+pub use self::transport::{Acceptor, Connect, Stream, Transport};
+pub use self::uv::UvIo;
+#[cfg(feature = "tokio")]
+pub use self::tokio::TokioIo;
+
+/// An I/O backend, responsible for persisting the raft state and for
+/// exchanging messages with the other servers.
+///
+/// # Safety
+///
+/// The `raft_io` returned by `as_raw` must be fully initialized and must stay
+/// at the same address for as long as the backend lives.
+pub unsafe trait Io {
+    /// Returns the `raft_io` to give to `raft_init`.
+    fn as_raw(&mut self) -> *mut raft_io;
+}
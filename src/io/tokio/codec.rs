@@ -0,0 +1,475 @@
+//! The wire format of the messages exchanged by the `TokioIo` backend.
+//!
+//! Every message is sent as a frame made of its length followed by the encoded message,
+//! all the integers are encoded in little endian. The snapshot data of an `InstallSnapshot`
+//! is the last field of its frame, it is written and read in chunks without being copied.
+
+use std::convert::TryInto;
+use std::{fmt, mem, ptr, slice};
+
+use canonical_raft_sys::*;
+use libc::{c_char, c_void};
+
+use crate::configuration::{self, Configuration};
+use crate::error::{RaftError, Result};
+use crate::fsm::slice_from_buf;
+
+/// Sent by a server when it connects to another one, before any message.
+pub(super) const PROTOCOL_VERSION: u64 = 1;
+
+/// An owned copy of a log entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Entry {
+    pub term: raft_term,
+    pub type_: u16,
+    pub data: Vec<u8>,
+}
+
+/// A decoded message, that does not borrow any memory from the raft library.
+#[derive(Debug)]
+pub(super) enum Message {
+    RequestVote(raft_request_vote),
+    RequestVoteResult(raft_request_vote_result),
+    AppendEntries {
+        term: raft_term,
+        prev_log_index: raft_index,
+        prev_log_term: raft_term,
+        leader_commit: raft_index,
+        entries: Vec<Entry>,
+    },
+    AppendEntriesResult(raft_append_entries_result),
+    InstallSnapshot {
+        term: raft_term,
+        last_index: raft_index,
+        last_term: raft_term,
+        conf: Vec<u8>,
+        conf_index: raft_index,
+        data: Buffer,
+    },
+    TimeoutNow(raft_timeout_now),
+}
+
+/// An encoded message, made of its head and of the snapshot data of an `InstallSnapshot`,
+/// which is not copied and must be written from the memory of the raft library.
+pub(super) struct Frame {
+    pub head: Vec<u8>,
+    pub data: raft_buffer,
+}
+
+/// A buffer allocated with raft_malloc, released when dropped unless given to the raft library.
+pub(super) struct Buffer(raft_buffer);
+
+impl Buffer {
+    pub(super) fn new(len: usize) -> Result<Buffer> {
+        let base = unsafe { raft_malloc(len.max(1)) };
+        if base.is_null() {
+            return Err(RaftError::NoMem(None));
+        }
+        Ok(Buffer(raft_buffer { base, len }))
+    }
+
+    pub(super) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.0.base as *mut u8, self.0.len) }
+    }
+
+    pub(super) fn into_raw(self) -> raft_buffer {
+        let buf = self.0;
+        mem::forget(self);
+        buf
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { raft_free(self.0.base) }
+    }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Buffer").field(&self.0.len).finish()
+    }
+}
+
+/// Encodes a message given by the raft library into a frame ready to be written.
+pub(super) unsafe fn encode(message: &raft_message) -> Result<Frame> {
+    let mut buf = vec![0; 8];
+    let mut data = raft_buffer { base: ptr::null_mut(), len: 0 };
+    let body = &message.__bindgen_anon_1;
+
+    put_u8(&mut buf, message.type_ as u8);
+    match message.type_ as u32 {
+        RAFT_IO_REQUEST_VOTE => {
+            let m = &body.request_vote;
+            put_u64(&mut buf, m.term);
+            put_u64(&mut buf, m.candidate_id);
+            put_u64(&mut buf, m.last_log_index);
+            put_u64(&mut buf, m.last_log_term);
+            put_u8(&mut buf, m.disrupt_leader as u8);
+        },
+        RAFT_IO_REQUEST_VOTE_RESULT => {
+            let m = &body.request_vote_result;
+            put_u64(&mut buf, m.term);
+            put_u8(&mut buf, m.vote_granted as u8);
+        },
+        RAFT_IO_APPEND_ENTRIES => {
+            let m = &body.append_entries;
+            put_u64(&mut buf, m.term);
+            put_u64(&mut buf, m.prev_log_index);
+            put_u64(&mut buf, m.prev_log_term);
+            put_u64(&mut buf, m.leader_commit);
+            put_u64(&mut buf, m.n_entries as u64);
+            for entry in entries_from_raw(m.entries, m.n_entries as usize) {
+                put_u64(&mut buf, entry.term);
+                put_u8(&mut buf, entry.type_ as u8);
+                put_bytes(&mut buf, &entry.data);
+            }
+        },
+        RAFT_IO_APPEND_ENTRIES_RESULT => {
+            let m = &body.append_entries_result;
+            put_u64(&mut buf, m.term);
+            put_u64(&mut buf, m.rejected);
+            put_u64(&mut buf, m.last_log_index);
+        },
+        RAFT_IO_INSTALL_SNAPSHOT => {
+            let m = &body.install_snapshot;
+            put_u64(&mut buf, m.term);
+            put_u64(&mut buf, m.last_index);
+            put_u64(&mut buf, m.last_term);
+            put_bytes(&mut buf, &configuration::encode(&m.conf)?);
+            put_u64(&mut buf, m.conf_index);
+            put_u64(&mut buf, m.data.len as u64);
+            data = m.data;
+        },
+        RAFT_IO_TIMEOUT_NOW => {
+            let m = &body.timeout_now;
+            put_u64(&mut buf, m.term);
+            put_u64(&mut buf, m.last_log_index);
+            put_u64(&mut buf, m.last_log_term);
+        },
+        _ => return Err(RaftError::Malformed(None)),
+    }
+
+    let len = (buf.len() - 8 + data.len) as u64;
+    buf[..8].copy_from_slice(&len.to_le_bytes());
+
+    Ok(Frame { head: buf, data })
+}
+
+/// Decodes the content of a frame, without its length prefix.
+pub(super) fn decode(mut bytes: &[u8]) -> Result<Message> {
+    let cursor = &mut bytes;
+
+    let message = match get_u8(cursor)? as u32 {
+        RAFT_IO_REQUEST_VOTE => Message::RequestVote(raft_request_vote {
+            term: get_u64(cursor)?,
+            candidate_id: get_u64(cursor)?,
+            last_log_index: get_u64(cursor)?,
+            last_log_term: get_u64(cursor)?,
+            disrupt_leader: get_u8(cursor)? != 0,
+        }),
+        RAFT_IO_REQUEST_VOTE_RESULT => Message::RequestVoteResult(raft_request_vote_result {
+            term: get_u64(cursor)?,
+            vote_granted: get_u8(cursor)? != 0,
+        }),
+        RAFT_IO_APPEND_ENTRIES => {
+            let term = get_u64(cursor)?;
+            let prev_log_index = get_u64(cursor)?;
+            let prev_log_term = get_u64(cursor)?;
+            let leader_commit = get_u64(cursor)?;
+            let n_entries = get_u64(cursor)?;
+            let mut entries = Vec::new();
+            for _ in 0..n_entries {
+                let term = get_u64(cursor)?;
+                let type_ = get_u8(cursor)? as u16;
+                let data = get_bytes(cursor)?.to_vec();
+                entries.push(Entry { term, type_, data });
+            }
+            Message::AppendEntries { term, prev_log_index, prev_log_term, leader_commit, entries }
+        },
+        RAFT_IO_APPEND_ENTRIES_RESULT => Message::AppendEntriesResult(raft_append_entries_result {
+            term: get_u64(cursor)?,
+            rejected: get_u64(cursor)?,
+            last_log_index: get_u64(cursor)?,
+        }),
+        RAFT_IO_INSTALL_SNAPSHOT => Message::InstallSnapshot {
+            term: get_u64(cursor)?,
+            last_index: get_u64(cursor)?,
+            last_term: get_u64(cursor)?,
+            conf: get_bytes(cursor)?.to_vec(),
+            conf_index: get_u64(cursor)?,
+            data: {
+                let bytes = get_bytes(cursor)?;
+                let mut data = Buffer::new(bytes.len())?;
+                data.as_mut_slice().copy_from_slice(bytes);
+                data
+            },
+        },
+        RAFT_IO_TIMEOUT_NOW => Message::TimeoutNow(raft_timeout_now {
+            term: get_u64(cursor)?,
+            last_log_index: get_u64(cursor)?,
+            last_log_term: get_u64(cursor)?,
+        }),
+        _ => return Err(RaftError::Malformed(None)),
+    };
+
+    Ok(message)
+}
+
+impl Message {
+    /// Converts the message into a `raft_message` to give to the raft library,
+    /// which takes the ownership of the memory allocated here.
+    pub(super) unsafe fn into_raw(
+        self,
+        server_id: raft_id,
+        server_address: *const c_char,
+    ) -> Result<raft_message>
+    {
+        let mut message: raft_message = mem::zeroed();
+        message.server_id = server_id;
+        message.server_address = server_address;
+        let body = &mut message.__bindgen_anon_1;
+
+        match self {
+            Message::RequestVote(m) => {
+                message.type_ = RAFT_IO_REQUEST_VOTE as u16;
+                body.request_vote = m;
+            },
+            Message::RequestVoteResult(m) => {
+                message.type_ = RAFT_IO_REQUEST_VOTE_RESULT as u16;
+                body.request_vote_result = m;
+            },
+            Message::AppendEntries { term, prev_log_index, prev_log_term, leader_commit, entries } => {
+                let (raw_entries, n_entries) = entries_to_raw(&entries)?;
+                message.type_ = RAFT_IO_APPEND_ENTRIES as u16;
+                body.append_entries = raft_append_entries {
+                    term,
+                    prev_log_index,
+                    prev_log_term,
+                    leader_commit,
+                    entries: raw_entries,
+                    n_entries: n_entries as u32,
+                };
+            },
+            Message::AppendEntriesResult(m) => {
+                message.type_ = RAFT_IO_APPEND_ENTRIES_RESULT as u16;
+                body.append_entries_result = m;
+            },
+            Message::InstallSnapshot { term, last_index, last_term, conf, conf_index, data } => {
+                let conf = Configuration::decode(&conf)?;
+                message.type_ = RAFT_IO_INSTALL_SNAPSHOT as u16;
+                body.install_snapshot = raft_install_snapshot {
+                    term,
+                    last_index,
+                    last_term,
+                    conf: conf.into_raw(),
+                    conf_index,
+                    data: data.into_raw(),
+                };
+            },
+            Message::TimeoutNow(m) => {
+                message.type_ = RAFT_IO_TIMEOUT_NOW as u16;
+                body.timeout_now = m;
+            },
+        }
+
+        Ok(message)
+    }
+}
+
+/// Copies the given entries into memory allocated with raft_malloc, all the entries
+/// share the same batch, as the raft library expects for loaded and received entries.
+pub(super) unsafe fn entries_to_raw(entries: &[Entry]) -> Result<(*mut raft_entry, usize)> {
+    if entries.is_empty() {
+        return Ok((ptr::null_mut(), 0));
+    }
+
+    let raw_entries = raft_malloc(mem::size_of::<raft_entry>() * entries.len()) as *mut raft_entry;
+    if raw_entries.is_null() {
+        return Err(RaftError::NoMem(None));
+    }
+
+    let size = entries.iter().map(|e| e.data.len()).sum::<usize>();
+    let batch = raft_malloc(size.max(1));
+    if batch.is_null() {
+        raft_free(raw_entries as *mut c_void);
+        return Err(RaftError::NoMem(None));
+    }
+
+    let mut offset = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let base = (batch as *mut u8).add(offset);
+        ptr::copy_nonoverlapping(entry.data.as_ptr(), base, entry.data.len());
+        raw_entries.add(i).write(raft_entry {
+            term: entry.term,
+            type_: entry.type_,
+            buf: raft_buffer { base: base as *mut c_void, len: entry.data.len() },
+            batch,
+        });
+        offset += entry.data.len();
+    }
+
+    Ok((raw_entries, entries.len()))
+}
+
+/// Copies entries that are owned by the raft library.
+pub(super) unsafe fn entries_from_raw(entries: *const raft_entry, n: usize) -> Vec<Entry> {
+    if n == 0 {
+        return Vec::new();
+    }
+
+    slice::from_raw_parts(entries, n)
+        .iter()
+        .map(|e| Entry { term: e.term, type_: e.type_, data: slice_from_buf(&e.buf).to_vec() })
+        .collect()
+}
+
+fn put_u8(buf: &mut Vec<u8>, value: u8) {
+    buf.push(value);
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn get_u8(cursor: &mut &[u8]) -> Result<u8> {
+    take(cursor, 1).map(|bytes| bytes[0])
+}
+
+fn get_u64(cursor: &mut &[u8]) -> Result<u64> {
+    take(cursor, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn get_bytes<'a>(cursor: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = get_u64(cursor)?;
+    take(cursor, len as usize)
+}
+
+fn take<'a>(cursor: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if cursor.len() < n {
+        return Err(RaftError::Malformed(None));
+    }
+    let (head, tail) = cursor.split_at(n);
+    *cursor = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the content of a frame as read by the other server, without its length.
+    unsafe fn content(frame: &Frame) -> Vec<u8> {
+        let mut content = frame.head[8..].to_vec();
+        content.extend_from_slice(slice_from_buf(&frame.data));
+        assert_eq!(u64::from_le_bytes(frame.head[..8].try_into().unwrap()), content.len() as u64);
+        content
+    }
+
+    #[test]
+    fn request_vote_round_trip() {
+        let mut message: raft_message = unsafe { mem::zeroed() };
+        message.type_ = RAFT_IO_REQUEST_VOTE as u16;
+        message.__bindgen_anon_1.request_vote = raft_request_vote {
+            term: 3,
+            candidate_id: 2,
+            last_log_index: 10,
+            last_log_term: 2,
+            disrupt_leader: true,
+        };
+
+        let content = unsafe { content(&encode(&message).unwrap()) };
+        match decode(&content).unwrap() {
+            Message::RequestVote(m) => {
+                assert_eq!((m.term, m.candidate_id, m.last_log_index, m.last_log_term), (3, 2, 10, 2));
+                assert!(m.disrupt_leader);
+            },
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn append_entries_round_trip() {
+        let mut data = [b"hello".to_vec(), Vec::new()];
+        let mut entries: Vec<_> = data
+            .iter_mut()
+            .map(|data| raft_entry {
+                term: 2,
+                type_: RAFT_COMMAND as u16,
+                buf: raft_buffer { base: data.as_mut_ptr() as *mut c_void, len: data.len() },
+                batch: ptr::null_mut(),
+            })
+            .collect();
+
+        let mut message: raft_message = unsafe { mem::zeroed() };
+        message.type_ = RAFT_IO_APPEND_ENTRIES as u16;
+        message.__bindgen_anon_1.append_entries = raft_append_entries {
+            term: 2,
+            prev_log_index: 4,
+            prev_log_term: 1,
+            leader_commit: 3,
+            entries: entries.as_mut_ptr(),
+            n_entries: entries.len() as u32,
+        };
+
+        let content = unsafe { content(&encode(&message).unwrap()) };
+        match decode(&content).unwrap() {
+            Message::AppendEntries { term, prev_log_index, prev_log_term, leader_commit, entries } => {
+                assert_eq!((term, prev_log_index, prev_log_term, leader_commit), (2, 4, 1, 3));
+                let expected: Vec<_> = data
+                    .iter()
+                    .map(|data| Entry { term: 2, type_: RAFT_COMMAND as u16, data: data.clone() })
+                    .collect();
+                assert_eq!(entries, expected);
+            },
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn install_snapshot_round_trip() {
+        let configuration = Configuration::builder().voter(1, "127.0.0.1:9001").build().unwrap();
+        let mut data = vec![42; 1000];
+
+        let mut message: raft_message = unsafe { mem::zeroed() };
+        message.type_ = RAFT_IO_INSTALL_SNAPSHOT as u16;
+        message.__bindgen_anon_1.install_snapshot = raft_install_snapshot {
+            term: 5,
+            last_index: 100,
+            last_term: 4,
+            // The message only borrows the configuration, it is released with it.
+            conf: unsafe { ptr::read(configuration.as_ptr()) },
+            conf_index: 1,
+            data: raft_buffer { base: data.as_mut_ptr() as *mut c_void, len: data.len() },
+        };
+
+        let frame = unsafe { encode(&message).unwrap() };
+        // The data is not copied into the head of the frame.
+        assert_eq!(frame.data.len, data.len());
+
+        match decode(&unsafe { content(&frame) }).unwrap() {
+            Message::InstallSnapshot { term, last_index, last_term, conf, conf_index, mut data } => {
+                assert_eq!((term, last_index, last_term, conf_index), (5, 100, 4, 1));
+                assert_eq!(conf, configuration.encode().unwrap());
+                assert_eq!(data.as_mut_slice(), &[42; 1000][..]);
+            },
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_malformed() {
+        let mut message: raft_message = unsafe { mem::zeroed() };
+        message.type_ = RAFT_IO_TIMEOUT_NOW as u16;
+        message.__bindgen_anon_1.timeout_now = raft_timeout_now { term: 1, last_log_index: 2, last_log_term: 1 };
+
+        let content = unsafe { content(&encode(&message).unwrap()) };
+        assert!(matches!(decode(&content[..content.len() - 1]), Err(RaftError::Malformed(_))));
+        assert!(matches!(decode(&[0xff]), Err(RaftError::Malformed(_))));
+    }
+}
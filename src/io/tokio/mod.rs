@@ -0,0 +1,957 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::ffi::{CStr, CString};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::mem::ManuallyDrop;
+use std::io::{self, Read};
+use std::{mem, ptr, slice};
+
+use canonical_raft_sys::*;
+use futures_channel::mpsc;
+use futures_util::future::{AbortHandle, Abortable};
+use futures_util::StreamExt;
+use libc::{c_char, c_int, c_uint, c_void};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::configuration::{self, Configuration};
+use crate::error::{RaftError, Result};
+use crate::fsm::{slice_from_buf, SNAPSHOT_CHUNK};
+use crate::store::{Log, LogKind, MemoryStore, SnapshotData, SnapshotMeta, Storage, CURRENT_TERM_KEY, VOTED_FOR_KEY};
+use self::codec::{Buffer, Entry, Frame, Message, PROTOCOL_VERSION};
+use super::Io;
+
+mod codec;
+
+/// The default maximum size of the messages received from the other servers, 64MiB.
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// The default maximum size of the snapshots received from the other servers, 1GiB.
+const DEFAULT_MAX_SNAPSHOT_SIZE: usize = 1024 * 1024 * 1024;
+
+/// The maximum length of the address sent by a server when it connects.
+const MAX_ADDRESS_LEN: usize = 4096;
+
+/// The time given to a connection to another server to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The time the acceptor waits after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A pure Rust I/O backend running on the Tokio runtime, that exchanges
+/// messages with the other servers over TCP.
+///
+/// The raft state is persisted in the given `Storage`, a `MemoryStore` by default.
+///
+/// Every raft function must be called from within a `tokio::task::LocalSet`,
+/// the tasks driving the backend are spawned on it.
+pub struct TokioIo {
+    raw: Box<raft_io>,
+    // The impl pointer of the raft_io points to the content of this Rc.
+    state: Rc<RefCell<State>>,
+}
+
+struct State {
+    io: *mut raft_io,
+    id: raft_id,
+    address: String,
+    store: Box<dyn Storage>,
+    // The index of the next entry to append, known once the state is loaded.
+    next_index: raft_index,
+    started_at: Instant,
+    tick_cb: raft_io_tick_cb,
+    recv_cb: raft_io_recv_cb,
+    // The outgoing messages queues, one per server we send messages to.
+    peers: HashMap<raft_id, mpsc::UnboundedSender<Outgoing>>,
+    // The long running tasks: the ticker, the listener and the connection readers.
+    tasks: HashMap<u64, AbortHandle>,
+    next_task: u64,
+    max_frame_size: usize,
+    max_snapshot_size: usize,
+    // The number of requests whose callback has not been invoked yet.
+    pending: usize,
+    closing: bool,
+    close_cb: raft_io_close_cb,
+}
+
+struct Outgoing {
+    frame: Frame,
+    req: *mut raft_io_send,
+    cb: raft_io_send_cb,
+}
+
+impl TokioIo {
+    /// Creates a backend that only keeps the raft state in memory.
+    pub fn new() -> TokioIo {
+        TokioIo::with_store(MemoryStore::new())
+    }
+
+    /// Creates a backend that persists the raft state in the given store.
+    pub fn with_store<S: Storage + 'static>(store: S) -> TokioIo {
+        let state = State {
+            io: ptr::null_mut(),
+            id: 0,
+            address: String::new(),
+            store: Box::new(store),
+            next_index: 1,
+            started_at: Instant::now(),
+            tick_cb: None,
+            recv_cb: None,
+            peers: HashMap::new(),
+            tasks: HashMap::new(),
+            next_task: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_snapshot_size: DEFAULT_MAX_SNAPSHOT_SIZE,
+            pending: 0,
+            closing: false,
+            close_cb: None,
+        };
+
+        let state = Rc::new(RefCell::new(state));
+        let mut raw: Box<raft_io> = Box::new(unsafe { mem::zeroed() });
+
+        raw.version = 1;
+        raw.impl_ = &*state as *const RefCell<State> as *mut c_void;
+        raw.init = Some(io_init);
+        raw.close = Some(io_close);
+        raw.load = Some(io_load);
+        raw.start = Some(io_start);
+        raw.bootstrap = Some(io_bootstrap);
+        raw.recover = Some(io_recover);
+        raw.set_term = Some(io_set_term);
+        raw.set_vote = Some(io_set_vote);
+        raw.send = Some(io_send);
+        raw.append = Some(io_append);
+        raw.truncate = Some(io_truncate);
+        raw.snapshot_put = Some(io_snapshot_put);
+        raw.snapshot_get = Some(io_snapshot_get);
+        raw.time = Some(io_time);
+        raw.random = Some(io_random);
+
+        state.borrow_mut().io = &mut *raw;
+
+        TokioIo { raw, state }
+    }
+
+    /// Sets the maximum size of the messages received from the other servers, 64MiB
+    /// by default, the connections sending bigger ones are closed. The snapshots are
+    /// bounded by `set_max_snapshot_size` instead.
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.state.borrow_mut().max_frame_size = size;
+    }
+
+    /// Sets the maximum size of the snapshots received from the other servers, 1GiB by default.
+    pub fn set_max_snapshot_size(&mut self, size: usize) {
+        self.state.borrow_mut().max_snapshot_size = size;
+    }
+}
+
+impl Default for TokioIo {
+    fn default() -> TokioIo {
+        TokioIo::new()
+    }
+}
+
+unsafe impl Io for TokioIo {
+    fn as_raw(&mut self) -> *mut raft_io {
+        &mut *self.raw
+    }
+}
+
+impl Drop for TokioIo {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.tasks.drain().for_each(|(_, task)| task.abort());
+        state.peers.clear();
+        state.io = ptr::null_mut();
+    }
+}
+
+unsafe fn state(io: *mut raft_io) -> Rc<RefCell<State>> {
+    // The impl pointer borrows the Rc owned by the TokioIo, we must not release it.
+    let state = ManuallyDrop::new(Rc::from_raw((*io).impl_ as *const RefCell<State>));
+    Rc::clone(&state)
+}
+
+unsafe fn set_errmsg(io: *mut raft_io, message: &str) {
+    let errmsg = &mut (*io).errmsg;
+    let len = message.len().min(errmsg.len() - 1);
+    ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, errmsg.as_mut_ptr(), len);
+    errmsg[len] = 0;
+}
+
+/// Reports an error to the raft library, along with its message if any.
+unsafe fn report(io: *mut raft_io, error: RaftError) -> c_int {
+    if let Some(message) = error.message() {
+        set_errmsg(io, message);
+    }
+    error.code()
+}
+
+/// Invokes a request callback on the next turn of the event loop,
+/// the raft library never expects them to be invoked synchronously.
+fn defer<G: FnOnce() + 'static>(state: &Rc<RefCell<State>>, callback: G) {
+    state.borrow_mut().pending += 1;
+
+    let state = Rc::clone(state);
+    tokio::task::spawn_local(async move {
+        callback();
+        finish(&state);
+    });
+}
+
+/// Marks a request as completed, invoking the close callback if it was the last one.
+fn finish(state: &Rc<RefCell<State>>) {
+    let (io, close_cb) = {
+        let mut state = state.borrow_mut();
+        state.pending -= 1;
+        if !state.closing || state.pending != 0 {
+            return;
+        }
+        (state.io, state.close_cb.take())
+    };
+
+    if let Some(close_cb) = close_cb {
+        unsafe { close_cb(io) }
+    }
+}
+
+unsafe extern "C" fn io_init(io: *mut raft_io, id: raft_id, address: *const c_char) -> c_int {
+    let state = state(io);
+    let mut state = state.borrow_mut();
+    state.id = id;
+    state.address = CStr::from_ptr(address).to_string_lossy().into_owned();
+    0
+}
+
+unsafe extern "C" fn io_close(io: *mut raft_io, cb: raft_io_close_cb) {
+    let state = state(io);
+
+    {
+        let mut state = state.borrow_mut();
+        state.closing = true;
+        state.close_cb = cb;
+        state.tasks.drain().for_each(|(_, task)| task.abort());
+        // The writers cancel their queued messages and stop.
+        state.peers.clear();
+    }
+
+    // Wait for the pending requests, or at least for the next turn of the event loop.
+    defer(&state, || ());
+}
+
+unsafe extern "C" fn io_load(
+    io: *mut raft_io,
+    term: *mut raft_term,
+    voted_for: *mut raft_id,
+    snapshot: *mut *mut raft_snapshot,
+    start_index: *mut raft_index,
+    entries: *mut *mut raft_entry,
+    n_entries: *mut usize,
+) -> c_int
+{
+    let state = state(io);
+    let mut state = state.borrow_mut();
+
+    let loaded = match load(&*state.store) {
+        Ok(loaded) => loaded,
+        Err(error) => return report(io, error),
+    };
+
+    let raw_snapshot = match state.store.latest() {
+        Ok(Some((meta, data))) => match snapshot_to_raw(&meta, data) {
+            Ok(raw_snapshot) => raw_snapshot,
+            Err(error) => return report(io, error),
+        },
+        Ok(None) => ptr::null_mut(),
+        Err(error) => return report(io, error),
+    };
+
+    let (raw_entries, n) = match codec::entries_to_raw(&loaded.entries) {
+        Ok(raw_entries) => raw_entries,
+        Err(error) => {
+            snapshot_free(raw_snapshot);
+            return report(io, error);
+        },
+    };
+
+    state.next_index = loaded.start_index + n as raft_index;
+
+    *term = loaded.term;
+    *voted_for = loaded.voted_for;
+    *snapshot = raw_snapshot;
+    *start_index = loaded.start_index;
+    *entries = raw_entries;
+    *n_entries = n;
+
+    0
+}
+
+struct Loaded {
+    term: raft_term,
+    voted_for: raft_id,
+    start_index: raft_index,
+    entries: Vec<Entry>,
+}
+
+fn load(store: &dyn Storage) -> Result<Loaded> {
+    let term = store.get_u64(CURRENT_TERM_KEY)?;
+    let voted_for = store.get_u64(VOTED_FOR_KEY)?;
+    let snapshot = store.latest_meta()?;
+
+    let first_index = store.first_index()?;
+    let last_index = store.last_index()?;
+
+    let (start_index, entries) = if first_index == 0 {
+        let start_index = snapshot.map_or(1, |meta| meta.index + 1);
+        (start_index, Vec::new())
+    } else {
+        let mut entries = Vec::new();
+        for index in first_index..=last_index {
+            match store.get_log(index)? {
+                Some(log) => entries.push(Entry { term: log.term, type_: log.kind.to_raw(), data: log.data }),
+                None => return Err(RaftError::Corrupt(Some(format!("missing log entry {}", index)))),
+            }
+        }
+        (first_index, entries)
+    };
+
+    Ok(Loaded { term, voted_for, start_index, entries })
+}
+
+unsafe extern "C" fn io_start(
+    io: *mut raft_io,
+    msecs: c_uint,
+    tick: raft_io_tick_cb,
+    recv: raft_io_recv_cb,
+) -> c_int
+{
+    let state = state(io);
+
+    let listener = match std::net::TcpListener::bind(&state.borrow().address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            set_errmsg(io, &format!("bind: {}", e));
+            return RaftError::IoErr(None).code();
+        },
+    };
+
+    let listener = match listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            set_errmsg(io, &format!("listen: {}", e));
+            return RaftError::IoErr(None).code();
+        },
+    };
+
+    let ticker = ticker(Rc::clone(&state), Duration::from_millis(msecs.into()));
+    let acceptor = acceptor(Rc::clone(&state), listener);
+
+    {
+        let mut state = state.borrow_mut();
+        state.tick_cb = tick;
+        state.recv_cb = recv;
+    }
+    spawn_task(&state, ticker);
+    spawn_task(&state, acceptor);
+
+    0
+}
+
+/// Spawns a long running task that is aborted when the backend is closed,
+/// its abort handle is released once it completes.
+fn spawn_task<T: std::future::Future<Output = ()> + 'static>(state: &Rc<RefCell<State>>, task: T) {
+    let (handle, registration) = AbortHandle::new_pair();
+    let id = {
+        let mut state = state.borrow_mut();
+        let id = state.next_task;
+        state.next_task += 1;
+        state.tasks.insert(id, handle);
+        id
+    };
+
+    let state = Rc::clone(state);
+    tokio::task::spawn_local(async move {
+        let _ = Abortable::new(task, registration).await;
+        state.borrow_mut().tasks.remove(&id);
+    });
+}
+
+async fn ticker(state: Rc<RefCell<State>>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let (io, tick_cb) = {
+            let state = state.borrow();
+            (state.io, state.tick_cb)
+        };
+        if let Some(tick_cb) = tick_cb {
+            unsafe { tick_cb(io) }
+        }
+    }
+}
+
+async fn acceptor(state: Rc<RefCell<State>>, mut listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let reader = reader(Rc::clone(&state), stream);
+                spawn_task(&state, reader);
+            },
+            // The errors persist until some resources are released, retrying
+            // at once would keep the other tasks from running.
+            Err(_) => tokio::time::delay_for(ACCEPT_BACKOFF).await,
+        }
+    }
+}
+
+/// Reads the messages sent by another server and hands them to the raft library.
+async fn reader(state: Rc<RefCell<State>>, mut stream: TcpStream) {
+    let (server_id, server_address) = match read_handshake(&mut stream).await {
+        Ok(handshake) => handshake,
+        Err(_) => return,
+    };
+
+    let limits = {
+        let state = state.borrow();
+        Limits { max_frame_size: state.max_frame_size, max_snapshot_size: state.max_snapshot_size }
+    };
+
+    loop {
+        let mut len = [0; 8];
+        if stream.read_exact(&mut len).await.is_err() {
+            return;
+        }
+
+        // The connection is closed on the first malformed frame.
+        let message = match read_message(&mut stream, u64::from_le_bytes(len), limits).await {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+
+        let (io, recv_cb) = {
+            let state = state.borrow();
+            (state.io, state.recv_cb)
+        };
+
+        unsafe {
+            if let Ok(mut message) = message.into_raw(server_id, server_address.as_ptr()) {
+                if let Some(recv_cb) = recv_cb {
+                    recv_cb(io, &mut message);
+                }
+            }
+        }
+    }
+}
+
+/// The sizes of the frames accepted from the other servers.
+#[derive(Clone, Copy)]
+struct Limits {
+    max_frame_size: usize,
+    max_snapshot_size: usize,
+}
+
+/// Reads the content of a frame of the given length.
+async fn read_message(stream: &mut TcpStream, len: u64, limits: Limits) -> Result<Message> {
+    let len = to_len(len)?;
+    if len == 0 {
+        return Err(malformed("empty frame"));
+    }
+
+    let mut type_ = [0; 1];
+    stream.read_exact(&mut type_).await?;
+
+    if type_[0] as u32 == RAFT_IO_INSTALL_SNAPSHOT {
+        return read_install_snapshot(stream, len - 1, limits).await;
+    }
+
+    if len > limits.max_frame_size {
+        return Err(malformed("frame too big"));
+    }
+
+    let mut frame = vec![0; len];
+    frame[0] = type_[0];
+    stream.read_exact(&mut frame[1..]).await?;
+
+    codec::decode(&frame)
+}
+
+/// Reads the snapshot data in chunks, straight into the buffer given to the raft library.
+async fn read_install_snapshot(stream: &mut TcpStream, len: usize, limits: Limits) -> Result<Message> {
+    let mut head = [0; 32];
+    stream.read_exact(&mut head).await?;
+    let term = u64::from_le_bytes(head[0..8].try_into().unwrap());
+    let last_index = u64::from_le_bytes(head[8..16].try_into().unwrap());
+    let last_term = u64::from_le_bytes(head[16..24].try_into().unwrap());
+    let conf_len = to_len(u64::from_le_bytes(head[24..32].try_into().unwrap()))?;
+
+    // The configuration is followed by its index and the length of the data.
+    let head_len = conf_len.checked_add(head.len() + 16).ok_or_else(|| malformed("invalid snapshot frame"))?;
+    if len < head_len || conf_len > limits.max_frame_size {
+        return Err(malformed("invalid snapshot frame"));
+    }
+    let mut conf = vec![0; conf_len];
+    stream.read_exact(&mut conf).await?;
+
+    let mut tail = [0; 16];
+    stream.read_exact(&mut tail).await?;
+    let conf_index = u64::from_le_bytes(tail[0..8].try_into().unwrap());
+    let data_len = to_len(u64::from_le_bytes(tail[8..16].try_into().unwrap()))?;
+
+    if head_len.checked_add(data_len) != Some(len) {
+        return Err(malformed("invalid snapshot frame"));
+    }
+    if data_len > limits.max_snapshot_size {
+        return Err(malformed("snapshot too big"));
+    }
+
+    let mut data = Buffer::new(data_len)?;
+    for chunk in data.as_mut_slice().chunks_mut(SNAPSHOT_CHUNK) {
+        stream.read_exact(chunk).await?;
+    }
+
+    Ok(Message::InstallSnapshot { term, last_index, last_term, conf, conf_index, data })
+}
+
+fn to_len(len: u64) -> Result<usize> {
+    len.try_into().map_err(|_| malformed("frame too big"))
+}
+
+fn malformed(message: &str) -> RaftError {
+    RaftError::Malformed(Some(message.to_owned()))
+}
+
+async fn read_handshake(stream: &mut TcpStream) -> io::Result<(raft_id, CString)> {
+    let mut header = [0; 24];
+    stream.read_exact(&mut header).await?;
+
+    let protocol = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let id = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let len = u64::from_le_bytes(header[16..24].try_into().unwrap());
+
+    if protocol != PROTOCOL_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown protocol version"));
+    }
+
+    if len > MAX_ADDRESS_LEN as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "address too long"));
+    }
+
+    let mut address = vec![0; len as usize];
+    stream.read_exact(&mut address).await?;
+    let address = CString::new(address).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok((id, address))
+}
+
+/// Writes the messages sent to another server, connecting to it when needed.
+async fn writer(
+    state: Rc<RefCell<State>>,
+    address: String,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+)
+{
+    let mut stream = None;
+
+    while let Some(message) = outgoing.next().await {
+        let status = if state.borrow().closing {
+            RaftError::Canceled(None).code()
+        } else {
+            match write_frame(&state, &mut stream, &address, &message.frame).await {
+                Ok(()) => 0,
+                Err(_) => {
+                    stream = None;
+                    RaftError::NoConnection(None).code()
+                },
+            }
+        };
+
+        if let Some(cb) = message.cb {
+            unsafe { cb(message.req, status) }
+        }
+        finish(&state);
+    }
+}
+
+async fn write_frame(
+    state: &Rc<RefCell<State>>,
+    stream: &mut Option<TcpStream>,
+    address: &str,
+    frame: &Frame,
+) -> io::Result<()>
+{
+    if stream.is_none() {
+        // The message is counted as pending until the connection resolves, closing waits for it.
+        let mut new_stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+        };
+
+        let handshake = {
+            let state = state.borrow();
+            let mut handshake = Vec::new();
+            handshake.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+            handshake.extend_from_slice(&state.id.to_le_bytes());
+            handshake.extend_from_slice(&(state.address.len() as u64).to_le_bytes());
+            handshake.extend_from_slice(state.address.as_bytes());
+            handshake
+        };
+
+        new_stream.write_all(&handshake).await?;
+        *stream = Some(new_stream);
+    }
+
+    let stream = stream.as_mut().unwrap();
+    stream.write_all(&frame.head).await?;
+
+    // The raft library keeps the snapshot alive until the send callback is invoked.
+    let data = unsafe { slice_from_buf(&frame.data) };
+    for chunk in data.chunks(SNAPSHOT_CHUNK) {
+        stream.write_all(chunk).await?;
+    }
+
+    Ok(())
+}
+
+unsafe extern "C" fn io_bootstrap(io: *mut raft_io, conf: *const raft_configuration) -> c_int {
+    let state = state(io);
+    let mut state = state.borrow_mut();
+
+    let result = configuration::encode(conf).and_then(|data| {
+        let store = &mut state.store;
+        if store.get_u64(CURRENT_TERM_KEY)? != 0 || store.last_index()? != 0 || store.latest_meta()?.is_some() {
+            return Err(RaftError::CantBootstrap(None));
+        }
+
+        store.set_u64(CURRENT_TERM_KEY, 1)?;
+        store.store_logs(&[Log { index: 1, term: 1, kind: LogKind::Change, data }])
+    });
+
+    match result {
+        Ok(()) => {
+            state.next_index = 2;
+            0
+        },
+        Err(error) => report(io, error),
+    }
+}
+
+unsafe extern "C" fn io_recover(io: *mut raft_io, conf: *const raft_configuration) -> c_int {
+    let state = state(io);
+    let mut state = state.borrow_mut();
+
+    let result = configuration::encode(conf).and_then(|data| {
+        let store = &mut state.store;
+        let term = store.get_u64(CURRENT_TERM_KEY)?;
+        let index = match store.last_index()? {
+            0 => store.latest_meta()?.map_or(1, |meta| meta.index + 1),
+            last_index => last_index + 1,
+        };
+        store.store_logs(&[Log { index, term, kind: LogKind::Change, data }])
+    });
+
+    match result {
+        Ok(()) => 0,
+        Err(error) => report(io, error),
+    }
+}
+
+unsafe extern "C" fn io_set_term(io: *mut raft_io, term: raft_term) -> c_int {
+    let state = state(io);
+    let store = &mut state.borrow_mut().store;
+
+    let result = store.set_u64(CURRENT_TERM_KEY, term).and_then(|_| store.set_u64(VOTED_FOR_KEY, 0));
+    match result {
+        Ok(()) => 0,
+        Err(error) => report(io, error),
+    }
+}
+
+unsafe extern "C" fn io_set_vote(io: *mut raft_io, server_id: raft_id) -> c_int {
+    let state = state(io);
+    let store = &mut state.borrow_mut().store;
+
+    match store.set_u64(VOTED_FOR_KEY, server_id) {
+        Ok(()) => 0,
+        Err(error) => report(io, error),
+    }
+}
+
+unsafe extern "C" fn io_send(
+    io: *mut raft_io,
+    req: *mut raft_io_send,
+    message: *const raft_message,
+    cb: raft_io_send_cb,
+) -> c_int
+{
+    let state = state(io);
+
+    let frame = match codec::encode(&*message) {
+        Ok(frame) => frame,
+        Err(error) => return error.code(),
+    };
+
+    (*req).cb = cb;
+
+    let mut state_ref = state.borrow_mut();
+    if state_ref.closing {
+        return RaftError::Canceled(None).code();
+    }
+
+    let server_id = (*message).server_id;
+    let outgoing = match state_ref.peers.get(&server_id) {
+        Some(outgoing) => outgoing.clone(),
+        None => {
+            let address = CStr::from_ptr((*message).server_address).to_string_lossy().into_owned();
+            let (sender, receiver) = mpsc::unbounded();
+            tokio::task::spawn_local(writer(Rc::clone(&state), address, receiver));
+            state_ref.peers.insert(server_id, sender.clone());
+            sender
+        },
+    };
+
+    // This runs in a callback of the raft library, it must not panic.
+    if outgoing.unbounded_send(Outgoing { frame, req, cb }).is_err() {
+        // The writer of this server stopped, a new one is spawned on the next send.
+        state_ref.peers.remove(&server_id);
+        return RaftError::IoErr(None).code();
+    }
+    state_ref.pending += 1;
+
+    0
+}
+
+unsafe extern "C" fn io_append(
+    io: *mut raft_io,
+    req: *mut raft_io_append,
+    entries: *const raft_entry,
+    n: c_uint,
+    cb: raft_io_append_cb,
+) -> c_int
+{
+    let state = state(io);
+
+    let status = {
+        let mut state = state.borrow_mut();
+        let first_index = state.next_index;
+
+        let result = codec::entries_from_raw(entries, n as usize)
+            .into_iter()
+            .zip(first_index..)
+            .map(|(entry, index)| {
+                let kind = LogKind::from_raw(entry.type_)?;
+                Ok(Log { index, term: entry.term, kind, data: entry.data })
+            })
+            .collect::<Result<Vec<_>>>()
+            .and_then(|logs| state.store.store_logs(&logs));
+
+        match result {
+            Ok(()) => {
+                state.next_index += n as raft_index;
+                0
+            },
+            Err(error) => report(io, error),
+        }
+    };
+
+    (*req).cb = cb;
+    defer(&state, move || {
+        if let Some(cb) = cb {
+            cb(req, status)
+        }
+    });
+
+    0
+}
+
+unsafe extern "C" fn io_truncate(io: *mut raft_io, index: raft_index) -> c_int {
+    let state = state(io);
+    let mut state = state.borrow_mut();
+
+    let result = state.store.last_index().and_then(|last_index| state.store.delete_range(index, last_index));
+    match result {
+        Ok(()) => {
+            state.next_index = index;
+            0
+        },
+        Err(error) => report(io, error),
+    }
+}
+
+unsafe extern "C" fn io_snapshot_put(
+    io: *mut raft_io,
+    trailing: c_uint,
+    req: *mut raft_io_snapshot_put,
+    snapshot: *const raft_snapshot,
+    cb: raft_io_snapshot_put_cb,
+) -> c_int
+{
+    let state = state(io);
+    let snapshot = &*snapshot;
+
+    let configuration = match configuration::encode(&snapshot.configuration) {
+        Ok(configuration) => configuration,
+        Err(error) => return report(io, error),
+    };
+
+    // The chunks spooled by the state machine are stored as they are.
+    let chunks: Vec<_> = (0..snapshot.n_bufs as usize)
+        .map(|i| slice_from_buf(&*snapshot.bufs.add(i)))
+        .collect();
+
+    let meta = SnapshotMeta {
+        index: snapshot.index,
+        term: snapshot.term,
+        configuration,
+        configuration_index: snapshot.configuration_index,
+    };
+
+    let status = {
+        let mut state = state.borrow_mut();
+        let trailing = trailing as raft_index;
+
+        let result = state.store.create(meta, &chunks).and_then(|_| {
+            let store = &mut state.store;
+            let first_index = store.first_index()?;
+            if first_index == 0 {
+                return Ok(());
+            }
+
+            // An installed snapshot replaces the whole log, otherwise we
+            // only delete the entries that are older than the trailing ones.
+            if trailing == 0 {
+                let last_index = store.last_index()?;
+                store.delete_range(first_index, last_index)
+            } else if snapshot.index > trailing {
+                store.delete_range(first_index, snapshot.index - trailing)
+            } else {
+                Ok(())
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                if trailing == 0 {
+                    state.next_index = snapshot.index + 1;
+                }
+                0
+            },
+            Err(error) => report(io, error),
+        }
+    };
+
+    (*req).cb = cb;
+    defer(&state, move || {
+        if let Some(cb) = cb {
+            cb(req, status)
+        }
+    });
+
+    0
+}
+
+unsafe extern "C" fn io_snapshot_get(
+    io: *mut raft_io,
+    req: *mut raft_io_snapshot_get,
+    cb: raft_io_snapshot_get_cb,
+) -> c_int
+{
+    let state = state(io);
+
+    (*req).cb = cb;
+    let state_ref = Rc::clone(&state);
+    defer(&state, move || {
+        let result = match state_ref.borrow().store.latest() {
+            Ok(Some((meta, data))) => snapshot_to_raw(&meta, data),
+            Ok(None) => Err(RaftError::NotFound(None)),
+            Err(error) => Err(error),
+        };
+
+        if let Some(cb) = cb {
+            match result {
+                Ok(snapshot) => cb(req, snapshot, 0),
+                Err(error) => cb(req, ptr::null_mut(), error.code()),
+            }
+        }
+    });
+
+    0
+}
+
+unsafe extern "C" fn io_time(io: *mut raft_io) -> raft_time {
+    let state = state(io);
+    let elapsed = state.borrow().started_at.elapsed();
+    elapsed.as_millis() as raft_time
+}
+
+unsafe extern "C" fn io_random(_io: *mut raft_io, min: c_int, max: c_int) -> c_int {
+    if max <= min {
+        return min;
+    }
+    rand::thread_rng().gen_range(min, max)
+}
+
+/// Reads a stored snapshot into memory allocated with raft_malloc,
+/// the raft library takes the ownership of it.
+///
+/// The raft library restores a snapshot from a single buffer, the data
+/// is streamed from the store into it without any intermediate copy.
+unsafe fn snapshot_to_raw(meta: &SnapshotMeta, mut data: SnapshotData) -> Result<*mut raft_snapshot> {
+    let configuration = Configuration::decode(&meta.configuration)?;
+
+    let len = match usize::try_from(data.size()) {
+        Ok(len) => len,
+        Err(_) => return Err(RaftError::TooBig(Some(format!("the snapshot of {} bytes is too big", data.size())))),
+    };
+
+    let base = raft_malloc(len.max(1));
+    if base.is_null() {
+        return Err(RaftError::NoMem(None));
+    }
+
+    if let Err(error) = data.read_exact(slice::from_raw_parts_mut(base as *mut u8, len)) {
+        raft_free(base);
+        return Err(error.into());
+    }
+
+    let bufs = raft_malloc(mem::size_of::<raft_buffer>()) as *mut raft_buffer;
+    if bufs.is_null() {
+        raft_free(base);
+        return Err(RaftError::NoMem(None));
+    }
+    bufs.write(raft_buffer { base, len });
+
+    let snapshot = raft_malloc(mem::size_of::<raft_snapshot>()) as *mut raft_snapshot;
+    if snapshot.is_null() {
+        raft_free((*bufs).base);
+        raft_free(bufs as *mut c_void);
+        return Err(RaftError::NoMem(None));
+    }
+
+    snapshot.write(raft_snapshot {
+        index: meta.index,
+        term: meta.term,
+        configuration: configuration.into_raw(),
+        configuration_index: meta.configuration_index,
+        bufs,
+        n_bufs: 1,
+    });
+
+    Ok(snapshot)
+}
+
+unsafe fn snapshot_free(snapshot: *mut raft_snapshot) {
+    if snapshot.is_null() {
+        return;
+    }
+
+    raft_configuration_close(&mut (*snapshot).configuration);
+    for i in 0..(*snapshot).n_bufs as usize {
+        raft_free((*(*snapshot).bufs.add(i)).base);
+    }
+    raft_free((*snapshot).bufs as *mut c_void);
+    raft_free(snapshot as *mut c_void);
+}
@@ -0,0 +1,520 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem::{self, ManuallyDrop};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::rc::{Rc, Weak};
+use std::{net, ptr};
+
+use canonical_raft_sys::*;
+use libc::{c_char, c_int, c_void};
+use libuv_sys2::*;
+
+use crate::error::{RaftError, Result};
+
+/// Establishes the connections between the servers on behalf of the libuv backend,
+/// this is the safe counterpart of `raft_uv_transport`.
+///
+/// Every method is called from the thread running the libuv loop, the connections
+/// must be handed back as libuv streams attached to this same loop.
+pub trait Transport {
+    /// Initializes the transport with the identity of this server.
+    fn init(&mut self, id: u64, address: &str) -> Result<()>;
+
+    /// Starts accepting the connections of the other servers,
+    /// they must be handed to the given acceptor.
+    fn listen(&mut self, acceptor: Acceptor) -> Result<()>;
+
+    /// Starts connecting to the given server, the outcome must be given to `Connect::complete`.
+    ///
+    /// Dropping the request without completing it reports a connection failure.
+    fn connect(&mut self, id: u64, address: &str, connect: Connect) -> Result<()>;
+
+    /// Stops accepting connections, the pending connect requests are canceled for you.
+    fn close(&mut self);
+}
+
+/// A connected libuv stream, whose ownership is transferred to the raft library.
+pub struct Stream {
+    raw: *mut uv_stream_s,
+}
+
+impl Stream {
+    /// Wraps a connected TCP socket in a stream attached to the given loop.
+    ///
+    /// # Safety
+    ///
+    /// The loop must be the one running the libuv backend.
+    pub unsafe fn from_tcp(loop_: *mut uv_loop_s, stream: net::TcpStream) -> Result<Stream> {
+        let tcp = raft_malloc(mem::size_of::<uv_tcp_s>()) as *mut uv_tcp_s;
+        if tcp.is_null() {
+            return Err(RaftError::NoMem(None));
+        }
+
+        let rv = uv_tcp_init(loop_, tcp);
+        if rv != 0 {
+            raft_free(tcp as *mut c_void);
+            return Err(uv_error(rv));
+        }
+
+        // From now on the handle must be closed by libuv before being released.
+        let result = Stream { raw: tcp as *mut uv_stream_s };
+
+        let fd = stream.into_raw_fd();
+        let rv = uv_tcp_open(tcp, fd);
+        if rv != 0 {
+            libc::close(fd);
+            return Err(uv_error(rv));
+        }
+
+        Ok(result)
+    }
+
+    /// Wraps a connected Unix domain socket in a stream attached to the given loop.
+    ///
+    /// # Safety
+    ///
+    /// The loop must be the one running the libuv backend.
+    pub unsafe fn from_unix(loop_: *mut uv_loop_s, stream: UnixStream) -> Result<Stream> {
+        let pipe = raft_malloc(mem::size_of::<uv_pipe_s>()) as *mut uv_pipe_s;
+        if pipe.is_null() {
+            return Err(RaftError::NoMem(None));
+        }
+
+        let rv = uv_pipe_init(loop_, pipe, 0);
+        if rv != 0 {
+            raft_free(pipe as *mut c_void);
+            return Err(uv_error(rv));
+        }
+
+        // From now on the handle must be closed by libuv before being released.
+        let result = Stream { raw: pipe as *mut uv_stream_s };
+
+        let fd = stream.into_raw_fd();
+        let rv = uv_pipe_open(pipe, fd);
+        if rv != 0 {
+            libc::close(fd);
+            return Err(uv_error(rv));
+        }
+
+        Ok(result)
+    }
+
+    /// Takes the ownership of an initialized libuv stream.
+    ///
+    /// # Safety
+    ///
+    /// The stream must be attached to the loop running the libuv backend and
+    /// must have been allocated with `raft_malloc`, the raft library releases it.
+    pub unsafe fn from_raw(raw: *mut uv_stream_s) -> Stream {
+        Stream { raw }
+    }
+
+    pub fn into_raw(self) -> *mut uv_stream_s {
+        let raw = self.raw;
+        mem::forget(self);
+        raw
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe { uv_close(self.raw as *mut uv_handle_s, Some(stream_close_cb)) }
+    }
+}
+
+unsafe extern "C" fn stream_close_cb(handle: *mut uv_handle_s) {
+    raft_free(handle as *mut c_void);
+}
+
+/// Hands the accepted connections to the raft library, it can be cloned and kept by the transport.
+#[derive(Clone)]
+pub struct Acceptor {
+    // The transport owns the acceptor, a strong reference would keep both alive forever.
+    shared: Weak<Shared>,
+}
+
+impl Acceptor {
+    /// Hands a connection initiated by the given server to the raft library.
+    ///
+    /// The connection is simply closed if the transport is already closed.
+    pub fn accept(&self, id: u64, address: &str, stream: Stream) {
+        let address = match CString::new(address) {
+            Ok(address) => address,
+            Err(_) => return,
+        };
+
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+
+        if shared.closed.get() {
+            return;
+        }
+
+        if let Some(accept_cb) = shared.accept_cb.get() {
+            unsafe { accept_cb(shared.raw, id, address.as_ptr(), stream.into_raw()) }
+        }
+    }
+}
+
+/// A pending connect request, that must be completed once the connection is established or has failed.
+pub struct Connect {
+    shared: Rc<Shared>,
+    key: Option<u64>,
+}
+
+impl Connect {
+    pub fn complete(mut self, result: Result<Stream>) {
+        self.finish(result);
+    }
+
+    fn finish(&mut self, result: Result<Stream>) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+
+        // The raft library does not expect the callback to be invoked
+        // from within the connect function, we always wait for the next turn.
+        let shared = Rc::clone(&self.shared);
+        unsafe {
+            defer(self.shared.loop_, move || {
+                // The request is not there anymore if it was canceled in the meantime,
+                // the stream is then dropped and therefore closed.
+                let request = shared.connects.borrow_mut().remove(&key);
+                if let Some((req, Some(cb))) = request {
+                    match result {
+                        Ok(stream) => cb(req, stream.into_raw(), 0),
+                        Err(error) => cb(req, ptr::null_mut(), error.code()),
+                    }
+                }
+            })
+        }
+    }
+}
+
+impl Drop for Connect {
+    fn drop(&mut self) {
+        self.finish(Err(RaftError::NoConnection(None)));
+    }
+}
+
+struct Shared {
+    raw: *mut raft_uv_transport,
+    loop_: *mut uv_loop_s,
+    transport: RefCell<Box<dyn Transport>>,
+    accept_cb: Cell<raft_uv_accept_cb>,
+    closed: Cell<bool>,
+    // The connect requests that were not completed yet.
+    connects: RefCell<HashMap<u64, (*mut raft_uv_connect, raft_uv_connect_cb)>>,
+    next_connect: Cell<u64>,
+}
+
+/// Exposes any `Transport` as a `raft_uv_transport` that can be given to the libuv backend.
+pub(crate) struct TransportAdapter {
+    // Boxed as the libuv backend keeps a pointer to it,
+    // its impl pointer points to the content of the Rc.
+    raw: Box<raft_uv_transport>,
+    _shared: Rc<Shared>,
+}
+
+impl TransportAdapter {
+    pub(crate) fn new<T: Transport + 'static>(loop_: *mut uv_loop_s, transport: T) -> TransportAdapter {
+        let mut raw: Box<raft_uv_transport> = Box::new(unsafe { mem::zeroed() });
+
+        let shared = Rc::new(Shared {
+            raw: &mut *raw,
+            loop_,
+            transport: RefCell::new(Box::new(transport)),
+            accept_cb: Cell::new(None),
+            closed: Cell::new(false),
+            connects: RefCell::new(HashMap::new()),
+            next_connect: Cell::new(0),
+        });
+
+        raw.impl_ = &*shared as *const Shared as *mut c_void;
+        raw.init = Some(transport_init);
+        raw.listen = Some(transport_listen);
+        raw.connect = Some(transport_connect);
+        raw.close = Some(transport_close);
+
+        TransportAdapter { raw, _shared: shared }
+    }
+
+    pub(crate) fn as_raw(&mut self) -> *mut raft_uv_transport {
+        &mut *self.raw
+    }
+}
+
+unsafe fn shared(t: *mut raft_uv_transport) -> Rc<Shared> {
+    // The impl pointer borrows the Rc owned by the adapter, we must not release it.
+    let shared = ManuallyDrop::new(Rc::from_raw((*t).impl_ as *const Shared));
+    Rc::clone(&shared)
+}
+
+/// Reports an error to the raft library, along with its message if any.
+unsafe fn report(t: *mut raft_uv_transport, error: RaftError) -> c_int {
+    if let Some(message) = error.message() {
+        let errmsg = &mut (*t).errmsg;
+        let len = message.len().min(errmsg.len() - 1);
+        ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, errmsg.as_mut_ptr(), len);
+        errmsg[len] = 0;
+    }
+    error.code()
+}
+
+unsafe extern "C" fn transport_init(t: *mut raft_uv_transport, id: raft_id, address: *const c_char) -> c_int {
+    let shared = shared(t);
+    let address = CStr::from_ptr(address).to_string_lossy();
+
+    let result = shared.transport.borrow_mut().init(id, &address);
+    match result {
+        Ok(()) => 0,
+        Err(error) => report(t, error),
+    }
+}
+
+unsafe extern "C" fn transport_listen(t: *mut raft_uv_transport, cb: raft_uv_accept_cb) -> c_int {
+    let shared = shared(t);
+    shared.accept_cb.set(cb);
+
+    let acceptor = Acceptor { shared: Rc::downgrade(&shared) };
+    let result = shared.transport.borrow_mut().listen(acceptor);
+    match result {
+        Ok(()) => 0,
+        Err(error) => report(t, error),
+    }
+}
+
+unsafe extern "C" fn transport_connect(
+    t: *mut raft_uv_transport,
+    req: *mut raft_uv_connect,
+    id: raft_id,
+    address: *const c_char,
+    cb: raft_uv_connect_cb,
+) -> c_int
+{
+    let shared = shared(t);
+    let address = CStr::from_ptr(address).to_string_lossy();
+
+    let key = shared.next_connect.get();
+    shared.next_connect.set(key + 1);
+    shared.connects.borrow_mut().insert(key, (req, cb));
+
+    let connect = Connect { shared: Rc::clone(&shared), key: Some(key) };
+    let result = shared.transport.borrow_mut().connect(id, &address, connect);
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            // The callback must not be invoked for a request that failed to start.
+            shared.connects.borrow_mut().remove(&key);
+            report(t, error)
+        },
+    }
+}
+
+unsafe extern "C" fn transport_close(t: *mut raft_uv_transport, cb: raft_uv_transport_close_cb) {
+    let shared = shared(t);
+    shared.closed.set(true);
+    shared.transport.borrow_mut().close();
+
+    let canceled: Vec<_> = shared.connects.borrow_mut().drain().map(|(_, request)| request).collect();
+    defer(shared.loop_, move || {
+        for (req, cb) in canceled {
+            if let Some(cb) = cb {
+                cb(req, ptr::null_mut(), RaftError::Canceled(None).code());
+            }
+        }
+        if let Some(cb) = cb {
+            cb(t);
+        }
+    });
+}
+
+struct Deferred {
+    timer: uv_timer_s,
+    callback: Option<Box<dyn FnOnce()>>,
+}
+
+/// Invokes the given callback on the next turn of the loop.
+unsafe fn defer<G: FnOnce() + 'static>(loop_: *mut uv_loop_s, callback: G) {
+    let deferred = Box::into_raw(Box::new(Deferred { timer: mem::zeroed(), callback: Some(Box::new(callback)) }));
+    uv_timer_init(loop_, &mut (*deferred).timer);
+    (*deferred).timer.data = deferred as *mut c_void;
+    uv_timer_start(&mut (*deferred).timer, Some(deferred_cb), 0, 0);
+}
+
+unsafe extern "C" fn deferred_cb(timer: *mut uv_timer_s) {
+    let deferred = (*timer).data as *mut Deferred;
+    if let Some(callback) = (*deferred).callback.take() {
+        callback();
+    }
+    uv_close(timer as *mut uv_handle_s, Some(deferred_close_cb));
+}
+
+unsafe extern "C" fn deferred_close_cb(handle: *mut uv_handle_s) {
+    drop(Box::from_raw((*handle).data as *mut Deferred));
+}
+
+fn uv_error(code: c_int) -> RaftError {
+    let message = unsafe { CStr::from_ptr(uv_strerror(code)) };
+    RaftError::IoErr(Some(message.to_string_lossy().into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connects a server to itself through Unix domain socket pairs.
+    struct PairTransport {
+        loop_: *mut uv_loop_s,
+        acceptor: Option<Acceptor>,
+    }
+
+    impl Transport for PairTransport {
+        fn init(&mut self, _id: u64, _address: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn listen(&mut self, acceptor: Acceptor) -> Result<()> {
+            self.acceptor = Some(acceptor);
+            Ok(())
+        }
+
+        fn connect(&mut self, id: u64, address: &str, connect: Connect) -> Result<()> {
+            let acceptor = self.acceptor.as_ref().ok_or(RaftError::NoConnection(None))?;
+            let (local, remote) = UnixStream::pair().map_err(|e| RaftError::IoErr(Some(e.to_string())))?;
+            unsafe {
+                acceptor.accept(id, address, Stream::from_unix(self.loop_, remote)?);
+                connect.complete(Stream::from_unix(self.loop_, local));
+            }
+            Ok(())
+        }
+
+        fn close(&mut self) {
+            self.acceptor = None;
+        }
+    }
+
+    type Accepted = Vec<(raft_id, String, *mut uv_stream_s)>;
+    type Connected = Option<(*mut uv_stream_s, c_int)>;
+
+    unsafe extern "C" fn accept_cb(t: *mut raft_uv_transport, id: raft_id, address: *const c_char, stream: *mut uv_stream_s) {
+        let accepted = &mut *((*t).data as *mut Accepted);
+        accepted.push((id, CStr::from_ptr(address).to_string_lossy().into_owned(), stream));
+    }
+
+    unsafe extern "C" fn connect_cb(req: *mut raft_uv_connect, stream: *mut uv_stream_s, status: c_int) {
+        *((*req).data as *mut Connected) = Some((stream, status));
+    }
+
+    unsafe fn fileno(stream: *mut uv_stream_s) -> c_int {
+        let mut fd = -1;
+        assert_eq!(uv_fileno(stream as *const uv_handle_s, &mut fd), 0);
+        fd
+    }
+
+    #[test]
+    fn connect_accept_send() {
+        unsafe {
+            let mut loop_: Box<uv_loop_s> = Box::new(mem::zeroed());
+            assert_eq!(uv_loop_init(&mut *loop_), 0);
+
+            let transport = PairTransport { loop_: &mut *loop_, acceptor: None };
+            let mut adapter = TransportAdapter::new(&mut *loop_, transport);
+            let t = adapter.as_raw();
+
+            let mut accepted = Accepted::new();
+            (*t).data = &mut accepted as *mut Accepted as *mut c_void;
+            let address = CString::new("127.0.0.1:9001").unwrap();
+            assert_eq!((*t).init.unwrap()(t, 1, address.as_ptr()), 0);
+            assert_eq!((*t).listen.unwrap()(t, Some(accept_cb)), 0);
+
+            let mut connected = Connected::None;
+            let mut req: raft_uv_connect = mem::zeroed();
+            req.data = &mut connected as *mut Connected as *mut c_void;
+            assert_eq!((*t).connect.unwrap()(t, &mut req, 1, address.as_ptr(), Some(connect_cb)), 0);
+
+            // The connect callback is only invoked on the next turn of the loop.
+            assert!(connected.is_none());
+            uv_run(&mut *loop_, uv_run_mode_UV_RUN_NOWAIT);
+
+            let (local, status) = connected.take().unwrap();
+            assert_eq!(status, 0);
+            assert_eq!(accepted.len(), 1);
+            let (id, remote_address, remote) = accepted.pop().unwrap();
+            assert_eq!((id, remote_address.as_str()), (1, "127.0.0.1:9001"));
+
+            // Both ends are connected to each other.
+            let message = b"hello";
+            assert_eq!(libc::write(fileno(local), message.as_ptr() as *const c_void, message.len()), 5);
+            let mut buf = [0u8; 16];
+            assert_eq!(libc::read(fileno(remote), buf.as_mut_ptr() as *mut c_void, buf.len()), 5);
+            assert_eq!(&buf[..5], message);
+
+            (*t).close.unwrap()(t, None);
+            drop(Stream::from_raw(local));
+            drop(Stream::from_raw(remote));
+            uv_run(&mut *loop_, uv_run_mode_UV_RUN_DEFAULT);
+
+            drop(adapter);
+            assert_eq!(uv_loop_close(&mut *loop_), 0);
+        }
+    }
+
+    /// Keeps its acceptor until it is dropped.
+    struct KeepingTransport {
+        acceptor: Option<Acceptor>,
+        dropped: Rc<Cell<bool>>,
+    }
+
+    impl Transport for KeepingTransport {
+        fn init(&mut self, _id: u64, _address: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn listen(&mut self, acceptor: Acceptor) -> Result<()> {
+            self.acceptor = Some(acceptor);
+            Ok(())
+        }
+
+        fn connect(&mut self, _id: u64, _address: &str, _connect: Connect) -> Result<()> {
+            Err(RaftError::NoConnection(None))
+        }
+
+        fn close(&mut self) {}
+    }
+
+    impl Drop for KeepingTransport {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    #[test]
+    fn transport_keeping_its_acceptor_is_released() {
+        unsafe {
+            let mut loop_: Box<uv_loop_s> = Box::new(mem::zeroed());
+            assert_eq!(uv_loop_init(&mut *loop_), 0);
+
+            let dropped = Rc::new(Cell::new(false));
+            let transport = KeepingTransport { acceptor: None, dropped: Rc::clone(&dropped) };
+            let mut adapter = TransportAdapter::new(&mut *loop_, transport);
+            let t = adapter.as_raw();
+
+            let address = CString::new("127.0.0.1:9001").unwrap();
+            assert_eq!((*t).init.unwrap()(t, 1, address.as_ptr()), 0);
+            assert_eq!((*t).listen.unwrap()(t, Some(accept_cb)), 0);
+
+            (*t).close.unwrap()(t, None);
+            uv_run(&mut *loop_, uv_run_mode_UV_RUN_DEFAULT);
+
+            drop(adapter);
+            assert!(dropped.get());
+            assert_eq!(uv_loop_close(&mut *loop_), 0);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::mem;
+
+use canonical_raft_sys::*;
+use libuv_sys2::uv_loop_s;
+
+use crate::error::{RaftError, Result};
+use super::transport::{Transport, TransportAdapter};
+use super::Io;
+
+/// The libuv based I/O backend, it stores the raft log in a directory
+/// and uses TCP, or any given `Transport`, to communicate with the other servers.
+pub struct UvIo {
+    // Both structs are boxed as the C implementation keeps pointers to them,
+    // the transport is only kept alive for the C implementation.
+    io: Box<raft_io>,
+    _transport: UvTransport,
+}
+
+enum UvTransport {
+    Tcp(Box<raft_uv_transport>),
+    Custom(TransportAdapter),
+}
+
+impl UvTransport {
+    fn as_raw(&mut self) -> *mut raft_uv_transport {
+        match self {
+            UvTransport::Tcp(transport) => &mut **transport,
+            UvTransport::Custom(adapter) => adapter.as_raw(),
+        }
+    }
+}
+
+impl Drop for UvTransport {
+    fn drop(&mut self) {
+        if let UvTransport::Tcp(transport) = self {
+            unsafe { raft_uv_tcp_close(&mut **transport) }
+        }
+    }
+}
+
+impl UvIo {
+    /// Creates a new I/O backend storing its data under `dir`.
+    ///
+    /// # Safety
+    ///
+    /// The loop must outlive the returned backend, and every raft function must be
+    /// called from the thread running this loop.
+    pub unsafe fn new<P: AsRef<Path>>(loop_: *mut uv_loop_s, dir: P) -> Result<UvIo> {
+        let mut transport: Box<raft_uv_transport> = Box::new(mem::zeroed());
+        let rv = raft_uv_tcp_init(&mut *transport, loop_);
+        if rv != 0 {
+            return Err(RaftError::from_errmsg(rv, &transport.errmsg));
+        }
+
+        UvIo::with_uv_transport(loop_, dir.as_ref(), UvTransport::Tcp(transport))
+    }
+
+    /// Creates a new I/O backend storing its data under `dir` and
+    /// communicating with the other servers using the given transport.
+    ///
+    /// # Safety
+    ///
+    /// The loop must outlive the returned backend, and every raft function must be
+    /// called from the thread running this loop.
+    pub unsafe fn with_transport<P, T>(loop_: *mut uv_loop_s, dir: P, transport: T) -> Result<UvIo>
+    where P: AsRef<Path>,
+          T: Transport + 'static,
+    {
+        let adapter = TransportAdapter::new(loop_, transport);
+        UvIo::with_uv_transport(loop_, dir.as_ref(), UvTransport::Custom(adapter))
+    }
+
+    unsafe fn with_uv_transport(loop_: *mut uv_loop_s, dir: &Path, mut transport: UvTransport) -> Result<UvIo> {
+        let dir = match CString::new(dir.as_os_str().as_bytes()) {
+            Ok(dir) => dir,
+            Err(_) => return Err(RaftError::Invalid(Some(format!("{} contains a NUL byte", dir.display())))),
+        };
+
+        let mut io: Box<raft_io> = Box::new(mem::zeroed());
+        let rv = raft_uv_init(&mut *io, loop_, dir.as_ptr(), transport.as_raw());
+        if rv != 0 {
+            return Err(RaftError::from_errmsg(rv, &io.errmsg));
+        }
+
+        Ok(UvIo { io, _transport: transport })
+    }
+
+    /// Sets the block size used for disk I/O, it must be a power of two.
+    pub fn set_block_size(&mut self, size: usize) {
+        unsafe { raft_uv_set_block_size(&mut *self.io, size) }
+    }
+
+    /// Sets the maximum size of the log segment files.
+    pub fn set_segment_size(&mut self, size: usize) {
+        unsafe { raft_uv_set_segment_size(&mut *self.io, size) }
+    }
+}
+
+unsafe impl Io for UvIo {
+    fn as_raw(&mut self) -> *mut raft_io {
+        &mut *self.io
+    }
+}
+
+impl Drop for UvIo {
+    fn drop(&mut self) {
+        // The raft instance using this backend must already be closed,
+        // this function only releases the memory, the transport is released after it.
+        unsafe { raft_uv_close(&mut *self.io) }
+    }
+}
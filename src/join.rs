@@ -0,0 +1,223 @@
+use std::future::Future;
+use std::time::Duration;
+
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+
+use crate::change::{Changes, Operation};
+use crate::error::{RaftError, Result};
+use crate::state::{now, progress, Role, State};
+
+/// The number of entries a new server can lag behind the leader to be considered caught up.
+const DEFAULT_THRESHOLD: u64 = 16;
+
+/// The time a new server has to catch up with the leader.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tracks the servers that were added as standbys, so that the leader replicates
+/// its log to them, and must catch up before being assigned their final role.
+pub(crate) struct Joins {
+    pending: Vec<Join>,
+    pub(crate) threshold: u64,
+    pub(crate) timeout: Duration,
+}
+
+struct Join {
+    id: u64,
+    role: Role,
+    // The time after which the server is considered too slow, in milliseconds.
+    deadline: raft_time,
+    // Resolves once the server is part of the configuration, `None` afterward.
+    added: Option<oneshot::Receiver<Result<()>>>,
+    // Resolves once the server is a standby, `None` afterward.
+    standby: Option<oneshot::Receiver<Result<()>>>,
+    sender: oneshot::Sender<Result<()>>,
+}
+
+impl Default for Joins {
+    fn default() -> Joins {
+        Joins { pending: Vec::new(), threshold: DEFAULT_THRESHOLD, timeout: DEFAULT_TIMEOUT }
+    }
+}
+
+impl Joins {
+    /// Adds the server as a standby then, once it has caught up, assigns it the given role.
+    ///
+    /// # Safety
+    ///
+    /// The pointers must stay valid until every change has completed,
+    /// `check` must then be called each time the leader progresses.
+    pub(crate) unsafe fn push(
+        &mut self,
+        changes: *mut Changes,
+        raft: *mut raft,
+        id: u64,
+        address: &str,
+        role: Role,
+    ) -> impl Future<Output = Result<()>>
+    {
+        let (sender, receiver) = oneshot::channel();
+
+        if role == Role::Spare {
+            Changes::submit(changes, raft, Operation::Add(id, address.to_owned()), sender);
+        } else {
+            // A spare is not replicated to, it could never catch up.
+            let (added_sender, added) = oneshot::channel();
+            Changes::submit(changes, raft, Operation::Add(id, address.to_owned()), added_sender);
+            let (standby_sender, standby) = oneshot::channel();
+            Changes::submit(changes, raft, Operation::Assign(id, Role::Standby), standby_sender);
+
+            let deadline = now(raft).unwrap_or(0) + self.timeout.as_millis() as raft_time;
+            self.pending.push(Join { id, role, deadline, added: Some(added), standby: Some(standby), sender });
+        }
+
+        async move {
+            match receiver.await {
+                Ok(result) => result,
+                Err(oneshot::Canceled) => Err(RaftError::Canceled(None)),
+            }
+        }
+    }
+
+    /// Promotes the servers that caught up and fails the ones that timed out.
+    pub(crate) unsafe fn check(&mut self, changes: *mut Changes, raft: *mut raft) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let now = now(raft).unwrap_or(0);
+        let threshold = self.threshold;
+        let mut i = 0;
+        while i < self.pending.len() {
+            match caught_up(&mut self.pending[i], raft, now, threshold) {
+                Ok(false) => i += 1,
+                Ok(true) => {
+                    let join = self.pending.swap_remove(i);
+                    if join.role == Role::Standby {
+                        let _ = join.sender.send(Ok(()));
+                    } else {
+                        Changes::submit(changes, raft, Operation::Assign(join.id, join.role), join.sender);
+                    }
+                },
+                Err(error) => {
+                    let join = self.pending.swap_remove(i);
+                    let _ = join.sender.send(Err(error));
+                },
+            }
+        }
+    }
+}
+
+/// Returns `true` if the server is a standby and has caught up with the leader.
+unsafe fn caught_up(join: &mut Join, raft: *mut raft, now: raft_time, threshold: u64) -> Result<bool> {
+    // The first change to fail is reported, the following ones fail because of it.
+    if !completed(&mut join.added)? || !completed(&mut join.standby)? {
+        return Ok(false);
+    }
+
+    if State::from_raw(raft_state(raft)) != State::Leader {
+        return Err(RaftError::LeadershipLost(None));
+    }
+
+    let (_, progress) = progress(raft).find(|(s, _)| s.id == join.id).ok_or(RaftError::BadId(None))?;
+
+    if progress.match_index + threshold >= raft_last_index(raft) {
+        return Ok(true);
+    }
+
+    if now >= join.deadline {
+        let message = format!("server {} did not catch up in time", join.id);
+        return Err(RaftError::Timeout(Some(message)));
+    }
+
+    Ok(false)
+}
+
+/// Returns `true` once the change has succeeded, it is then forgotten.
+fn completed(change: &mut Option<oneshot::Receiver<Result<()>>>) -> Result<bool> {
+    if let Some(receiver) = change {
+        match receiver.try_recv() {
+            Ok(None) => return Ok(false),
+            Ok(Some(result)) => result?,
+            Err(oneshot::Canceled) => return Err(RaftError::Canceled(None)),
+        }
+        *change = None;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::{cluster, Counter};
+    use crate::testing::Fixture;
+
+    /// Adds an empty server to the cluster and starts it, returns its index.
+    fn grow(fixture: &mut Fixture<Counter>) -> usize {
+        let i = fixture.grow(Counter(0)).unwrap();
+        assert_eq!(unsafe { raft_start(fixture.raft(i)) }, 0);
+        i
+    }
+
+    /// Steps the cluster, checking the joins, until the join completes.
+    fn settle(
+        fixture: &mut Fixture<Counter>,
+        joins: &mut Joins,
+        changes: *mut Changes,
+        joined: impl Future<Output = Result<()>>,
+    ) -> Option<Result<()>>
+    {
+        let raft = fixture.raft(0);
+        let mut joined = Box::pin(joined);
+        let mut result = None;
+        fixture.step_until(Duration::from_secs(10), |_| {
+            unsafe { joins.check(changes, raft) };
+            result = (&mut joined).now_or_never();
+            result.is_some()
+        });
+        result
+    }
+
+    unsafe fn role(raft: *mut raft, id: u64) -> Option<Role> {
+        progress(raft).find(|(s, _)| s.id == id).map(|(s, _)| Role::from_raw(s.role).unwrap())
+    }
+
+    #[test]
+    fn server_is_promoted_once_caught_up() {
+        let mut fixture = cluster();
+        let applied = fixture.apply(0, 5u64.to_le_bytes().to_vec());
+        assert!(fixture.step_until(Duration::from_secs(2), |f| f.fsm(0).0 == 5));
+        assert!(applied.now_or_never().unwrap().is_ok());
+        let i = grow(&mut fixture);
+
+        let mut changes = Box::new(Changes::default());
+        let mut joins = Joins { threshold: 0, ..Joins::default() };
+        let raft = fixture.raft(0);
+        let joined = unsafe { joins.push(&mut *changes, raft, 4, "4", Role::Voter) };
+
+        assert_eq!(settle(&mut fixture, &mut joins, &mut *changes, joined), Some(Ok(())));
+        assert_eq!(unsafe { role(raft, 4) }, Some(Role::Voter));
+        assert_eq!(fixture.fsm(i).0, 5);
+    }
+
+    #[test]
+    fn server_not_catching_up_times_out() {
+        let mut fixture = cluster();
+        let i = grow(&mut fixture);
+        fixture.disconnect(0, i);
+        fixture.disconnect(i, 0);
+
+        let mut changes = Box::new(Changes::default());
+        let mut joins = Joins { threshold: 0, timeout: Duration::from_secs(1), ..Joins::default() };
+        let raft = fixture.raft(0);
+        let joined = unsafe { joins.push(&mut *changes, raft, 4, "4", Role::Voter) };
+
+        let result = settle(&mut fixture, &mut joins, &mut *changes, joined);
+        assert!(matches!(result, Some(Err(RaftError::Timeout(_)))));
+        assert!(joins.pending.is_empty());
+        // The server stays a standby, still replicated to once it is reachable.
+        assert_eq!(unsafe { role(raft, 4) }, Some(Role::Standby));
+    }
+}
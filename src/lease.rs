@@ -0,0 +1,321 @@
+use std::collections::{HashMap, VecDeque};
+use std::slice;
+use std::time::Duration;
+
+use canonical_raft_sys::*;
+
+use crate::error::{RaftError, Result};
+use crate::state::{now, Role, State};
+
+/// The maximum number of unanswered requests whose send time is kept for a follower.
+///
+/// Past this limit the new requests are not recorded, the answers are then matched
+/// with older requests, which can only make the lease shorter.
+const MAX_PENDING_SENDS: usize = 1024;
+
+/// Tracks the lease of a leader, during which no other server can have been elected.
+///
+/// A follower does not start an election before an election timeout elapsed since it
+/// last heard from the leader. The answers of a follower are matched in order with the
+/// requests sent to it, once a quorum of voters answered, the leader keeps its leadership
+/// for an election timeout after the oldest of the matching requests was sent, minus
+/// the allowed clock drift. A lost request or answer only shifts the matching to older
+/// requests, shortening the lease.
+#[derive(Default)]
+pub(crate) struct Lease {
+    /// The maximum drift between the clocks of the servers, `None` disables the lease.
+    max_drift: Option<Duration>,
+    // The term in which the lease was acquired.
+    term: u64,
+    // The index of the first entry this leader could have appended.
+    term_start_index: u64,
+    // The requests sent to each follower during this term.
+    followers: HashMap<u64, Follower>,
+    // The time at which the lease expires, in milliseconds.
+    expiry: raft_time,
+}
+
+#[derive(Default)]
+struct Follower {
+    // The send times of the requests that are not answered yet, oldest first.
+    pending: VecDeque<raft_time>,
+    // The send time of the last answered request.
+    acknowledged: raft_time,
+}
+
+impl Lease {
+    /// Enables the lease given the maximum clock drift, it must be lower than the election timeout.
+    pub(crate) unsafe fn set_max_drift(&mut self, raft: *mut raft, max_drift: Option<Duration>) -> Result<()> {
+        if let Some(max_drift) = max_drift {
+            check_drift(max_drift, Duration::from_millis((*raft).election_timeout.into()))?;
+        }
+
+        self.max_drift = max_drift;
+        self.expiry = 0;
+        Ok(())
+    }
+
+    /// Fails unless the given election timeout is greater than the drift of the enabled lease.
+    pub(crate) fn check_election_timeout(&self, election_timeout: Duration) -> Result<()> {
+        match self.max_drift {
+            Some(max_drift) => check_drift(max_drift, election_timeout),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the send time of a request that the follower answers with an AppendEntriesResult.
+    pub(crate) unsafe fn sent(&mut self, raft: *mut raft, message: *const raft_message) {
+        let type_ = (*message).type_ as u32;
+        if self.max_drift.is_none() || (type_ != RAFT_IO_APPEND_ENTRIES && type_ != RAFT_IO_INSTALL_SNAPSHOT) {
+            return;
+        }
+
+        if !self.renew_term(raft) {
+            return;
+        }
+
+        let now = match now(raft) {
+            Some(now) => now,
+            None => return,
+        };
+
+        let follower = self.followers.entry((*message).server_id).or_default();
+        if follower.pending.len() < MAX_PENDING_SENDS {
+            follower.pending.push_back(now);
+        }
+    }
+
+    /// Matches an AppendEntriesResult with the oldest request the follower did not answer yet.
+    pub(crate) unsafe fn received(&mut self, raft: *mut raft, message: *const raft_message) {
+        if self.max_drift.is_none() || (*message).type_ as u32 != RAFT_IO_APPEND_ENTRIES_RESULT {
+            return;
+        }
+
+        let result = &(*message).__bindgen_anon_1.append_entries_result;
+        if !self.renew_term(raft) || result.term != (*raft).current_term {
+            return;
+        }
+
+        if let Some(follower) = self.followers.get_mut(&(*message).server_id) {
+            if let Some(sent) = follower.pending.pop_front() {
+                follower.acknowledged = sent;
+            }
+        }
+    }
+
+    /// Forgets the requests of the previous terms, returns `false` if this server is not the leader.
+    unsafe fn renew_term(&mut self, raft: *mut raft) -> bool {
+        if State::from_raw(raft_state(raft)) != State::Leader {
+            self.term = 0;
+            self.followers.clear();
+            self.expiry = 0;
+            return false;
+        }
+
+        if self.term != (*raft).current_term {
+            self.term = (*raft).current_term;
+            self.term_start_index = raft_last_index(raft) + 1;
+            self.followers.clear();
+            self.expiry = 0;
+        }
+
+        true
+    }
+
+    /// Extends the lease up to the oldest request answered by a quorum of voters.
+    pub(crate) unsafe fn observe(&mut self, raft: *mut raft) {
+        let max_drift = match self.max_drift {
+            Some(max_drift) => max_drift.as_millis() as raft_time,
+            None => return,
+        };
+
+        if !self.renew_term(raft) {
+            return;
+        }
+
+        let now = match now(raft) {
+            Some(now) => now,
+            None => return,
+        };
+
+        let r = &*raft;
+        let servers = slice::from_raw_parts(r.configuration.servers, r.configuration.n as usize);
+        let mut acknowledged: Vec<_> = servers
+            .iter()
+            .filter(|s| s.role == Role::Voter.to_raw())
+            .map(|s| match self.followers.get(&s.id) {
+                _ if s.id == r.id => now,
+                Some(follower) => follower.acknowledged,
+                None => 0,
+            })
+            .collect();
+
+        if acknowledged.is_empty() {
+            return;
+        }
+
+        // The quorum is made of the voters that answered the most recent requests.
+        acknowledged.sort_unstable_by(|a, b| b.cmp(a));
+        let anchor = acknowledged[acknowledged.len() / 2];
+        if anchor != 0 {
+            let expiry = (anchor + r.election_timeout as raft_time).saturating_sub(max_drift);
+            self.expiry = self.expiry.max(expiry);
+        }
+    }
+
+    /// Returns `true` if the state machine of the leader can be read without a round trip.
+    pub(crate) unsafe fn is_valid(&self, raft: *mut raft) -> bool {
+        if self.max_drift.is_none() || State::from_raw(raft_state(raft)) != State::Leader {
+            return false;
+        }
+
+        // The target of a leadership transfer can be elected before the lease expires.
+        let r = &*raft;
+        if !r.transfer.is_null() {
+            return false;
+        }
+
+        let now = match now(raft) {
+            Some(now) => now,
+            None => return false,
+        };
+
+        // An entry of the current term must be applied, proving that this leader
+        // knows every committed entry, and every committed entry must be applied.
+        r.current_term == self.term
+            && now < self.expiry
+            && r.last_applied >= self.term_start_index
+            && r.last_applied >= r.commit_index
+    }
+}
+
+fn check_drift(max_drift: Duration, election_timeout: Duration) -> Result<()> {
+    if max_drift >= election_timeout {
+        let message = format!("the clock drift {:?} is not lower than the election timeout", max_drift);
+        return Err(RaftError::Invalid(Some(message)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::{cluster, Counter};
+    use crate::testing::Fixture;
+    use crate::transfer::transfer;
+
+    const DRIFT: Duration = Duration::from_millis(100);
+
+    fn message(type_: u32, server_id: u64, term: u64) -> raft_message {
+        let mut message: raft_message = unsafe { mem::zeroed() };
+        message.type_ = type_ as _;
+        message.server_id = server_id;
+        message.__bindgen_anon_1.append_entries_result.term = term;
+        message
+    }
+
+    /// Starts a lease on the leader of a three server cluster, acknowledged by the second server.
+    fn leased() -> (Fixture<Counter>, Lease) {
+        let mut fixture = cluster();
+        let raft = fixture.raft(0);
+        let mut lease = Lease::default();
+        unsafe {
+            lease.set_max_drift(raft, Some(DRIFT)).unwrap();
+            lease.sent(raft, &message(RAFT_IO_APPEND_ENTRIES, 2, 0));
+            lease.received(raft, &message(RAFT_IO_APPEND_ENTRIES_RESULT, 2, (*raft).current_term));
+            lease.observe(raft);
+        }
+
+        // An entry of the current term must be applied before the lease is used.
+        let applied = fixture.apply(0, 1u64.to_le_bytes().to_vec());
+        assert!(fixture.step_until(Duration::from_secs(1), |f| f.fsm(0).0 == 1));
+        assert!(applied.now_or_never().unwrap().is_ok());
+        (fixture, lease)
+    }
+
+    #[test]
+    fn lease_is_only_renewed_by_answers_to_requests_of_the_current_term() {
+        let mut fixture = cluster();
+        let raft = fixture.raft(0);
+        let term = unsafe { (*raft).current_term };
+        let mut lease = Lease::default();
+        unsafe {
+            lease.set_max_drift(raft, Some(DRIFT)).unwrap();
+
+            // The answer of a previous term does not acknowledge the request.
+            lease.sent(raft, &message(RAFT_IO_APPEND_ENTRIES, 2, 0));
+            lease.received(raft, &message(RAFT_IO_APPEND_ENTRIES_RESULT, 2, term - 1));
+            lease.observe(raft);
+            assert_eq!(lease.expiry, 0);
+
+            lease.sent(raft, &message(RAFT_IO_APPEND_ENTRIES, 3, 0));
+        }
+
+        // The request was sent in a previous term once this server is elected again.
+        fixture.depose();
+        fixture.elect(0);
+        unsafe {
+            let term = (*raft).current_term;
+            lease.received(raft, &message(RAFT_IO_APPEND_ENTRIES_RESULT, 2, term));
+            lease.received(raft, &message(RAFT_IO_APPEND_ENTRIES_RESULT, 3, term));
+            lease.observe(raft);
+            assert_eq!(lease.expiry, 0);
+            assert!(!lease.is_valid(raft));
+        }
+    }
+
+    #[test]
+    fn lease_expires_with_the_acknowledgements() {
+        let (mut fixture, mut lease) = leased();
+        let raft = fixture.raft(0);
+        assert!(unsafe { lease.is_valid(raft) });
+
+        let election_timeout = Duration::from_millis(unsafe { (*raft).election_timeout }.into());
+        fixture.step_until_elapsed(election_timeout - DRIFT);
+        unsafe {
+            lease.observe(raft);
+            assert!(!lease.is_valid(raft));
+        }
+    }
+
+    #[test]
+    fn lease_is_invalidated_by_a_transfer_and_the_step_down() {
+        let (mut fixture, mut lease) = leased();
+        let raft = fixture.raft(0);
+        assert!(unsafe { lease.is_valid(raft) });
+
+        let mut transferred = Box::pin(unsafe { transfer(raft, Some(2)) });
+        assert!(!unsafe { lease.is_valid(raft) });
+
+        assert!(fixture.step_until(Duration::from_secs(5), |_| (&mut transferred).now_or_never().is_some()));
+        unsafe {
+            lease.observe(raft);
+            assert!(!lease.is_valid(raft));
+            assert_eq!(lease.expiry, 0);
+        }
+    }
+
+    #[test]
+    fn drift_must_be_lower_than_the_election_timeout() {
+        let mut fixture = Fixture::new(vec![Counter(0)]).unwrap();
+        let raft = fixture.raft(0);
+        let election_timeout = Duration::from_millis(unsafe { (*raft).election_timeout }.into());
+
+        let mut lease = Lease::default();
+        let result = unsafe { lease.set_max_drift(raft, Some(election_timeout)) };
+        assert!(matches!(result, Err(RaftError::Invalid(_))));
+        assert!(lease.max_drift.is_none());
+
+        let drift = election_timeout / 2;
+        assert_eq!(unsafe { lease.set_max_drift(raft, Some(drift)) }, Ok(()));
+        assert_eq!(lease.max_drift, Some(drift));
+
+        // The election timeout cannot be lowered under the drift afterward.
+        assert!(matches!(lease.check_election_timeout(drift), Err(RaftError::Invalid(_))));
+        assert_eq!(lease.check_election_timeout(election_timeout), Ok(()));
+    }
+}
@@ -1,3 +1,39 @@
+mod barrier;
+mod batch;
+mod change;
+mod configuration;
+mod error;
+mod forward;
+mod fsm;
+mod join;
+mod lease;
+mod limits;
+mod raft;
+mod state;
+mod transfer;
+#[cfg(any(feature = "tracing", feature = "log"))]
+mod tracer;
+mod watch;
+
+pub mod heap;
+pub mod io;
+pub mod session;
+pub mod store;
+pub mod testing;
+
+pub use self::configuration::{Configuration, ConfigurationBuilder, Server};
+pub use self::error::{RaftError, Result};
+pub use self::forward::Forward;
+pub use self::fsm::{Fsm, FsmAdapter, SNAPSHOT_CHUNK};
+pub use self::io::{Io, UvIo};
+pub use self::limits::Backpressure;
+pub use self::raft::Raft;
+pub use self::state::{Role, State, Status};
+#[cfg(any(feature = "tracing", feature = "log"))]
+pub use self::tracer::Tracer;
+pub use self::store::{LogStore, MemoryStore, SnapshotStore, StableStore};
+pub use self::watch::StateChange;
+
 #[cfg(test)]
 mod tests {
     #[test]
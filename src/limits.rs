@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+
+use crate::error::{RaftError, Result};
+
+type Sender<T> = oneshot::Sender<Result<(T, u64)>>;
+
+/// What `Raft::apply` does when the limits set by `Raft::set_apply_limits` are reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// The command waits until the previous ones are committed and applied.
+    Wait,
+    /// The command fails immediately with `RaftError::Busy`.
+    Fail,
+}
+
+/// The apply limit that a command would exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limit {
+    InFlight,
+    UncommittedBytes,
+}
+
+impl Limit {
+    fn message(self) -> &'static str {
+        match self {
+            Limit::InFlight => "too many commands are waiting to be applied",
+            Limit::UncommittedBytes => "too many bytes are waiting to be committed",
+        }
+    }
+}
+
+/// Bounds the commands the leader has not committed and applied yet.
+pub(crate) struct Limits<T> {
+    pub(crate) max_in_flight: Option<usize>,
+    pub(crate) max_uncommitted_bytes: Option<usize>,
+    pub(crate) backpressure: Backpressure,
+    // The commands waiting for capacity, in the order they were submitted.
+    waiting: VecDeque<(Bytes, Sender<T>)>,
+    // The last index and the size of the submissions that are not committed yet, oldest first.
+    uncommitted: VecDeque<(u64, usize)>,
+    uncommitted_bytes: usize,
+}
+
+impl<T> Default for Limits<T> {
+    fn default() -> Limits<T> {
+        Limits {
+            max_in_flight: None,
+            max_uncommitted_bytes: None,
+            backpressure: Backpressure::Wait,
+            waiting: VecDeque::new(),
+            uncommitted: VecDeque::new(),
+            uncommitted_bytes: 0,
+        }
+    }
+}
+
+impl<T> Limits<T> {
+    /// Returns `true` if a command of `len` bytes can be submitted, given
+    /// the number of commands and bytes waiting in the apply batch.
+    pub(crate) unsafe fn has_capacity(&self, raft: *mut raft, batched: usize, batched_bytes: usize, len: usize) -> bool {
+        self.reached(raft, batched, batched_bytes, len).is_none()
+    }
+
+    /// Returns the limit that a command of `len` bytes would exceed, if any.
+    pub(crate) unsafe fn reached(
+        &self,
+        raft: *mut raft,
+        batched: usize,
+        batched_bytes: usize,
+        len: usize,
+    ) -> Option<Limit>
+    {
+        if let Some(max) = self.max_in_flight {
+            // Every entry that is not applied yet, including the barriers and configurations.
+            let in_flight = (raft_last_index(raft) - (*raft).last_applied) as usize + batched;
+            if in_flight >= max {
+                return Some(Limit::InFlight);
+            }
+        }
+
+        if let Some(max) = self.max_uncommitted_bytes {
+            let uncommitted = self.uncommitted_bytes + batched_bytes;
+            // A command bigger than the limit is accepted when nothing is uncommitted.
+            if uncommitted > 0 && uncommitted + len > max {
+                return Some(Limit::UncommittedBytes);
+            }
+        }
+
+        None
+    }
+
+    /// Queues a command that exceeds the given limit or fails it, depending on the backpressure.
+    pub(crate) fn overflow(&mut self, command: Bytes, sender: Sender<T>, limit: Limit) {
+        match self.backpressure {
+            Backpressure::Wait => self.wait(command, sender),
+            Backpressure::Fail => {
+                let _ = sender.send(Err(RaftError::Busy(Some(limit.message().to_owned()))));
+            },
+        }
+    }
+
+    /// Queues a command behind the ones already waiting for capacity.
+    pub(crate) fn wait(&mut self, command: Bytes, sender: Sender<T>) {
+        self.waiting.push_back((command, sender));
+    }
+
+    pub(crate) fn has_waiting(&self) -> bool {
+        !self.waiting.is_empty()
+    }
+
+    /// Returns the first waiting command if there is now capacity for it.
+    pub(crate) unsafe fn pop_ready(
+        &mut self,
+        raft: *mut raft,
+        batched: usize,
+        batched_bytes: usize,
+    ) -> Option<(Bytes, Sender<T>)>
+    {
+        let len = self.waiting.front()?.0.len();
+        if self.has_capacity(raft, batched, batched_bytes, len) {
+            self.waiting.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Records the commands of `bytes` bytes appended to the log up to `last_index`.
+    pub(crate) fn submitted(&mut self, last_index: u64, bytes: usize) {
+        if bytes > 0 {
+            self.uncommitted.push_back((last_index, bytes));
+            self.uncommitted_bytes += bytes;
+        }
+    }
+
+    /// Forgets the commands committed up to `commit_index`.
+    pub(crate) fn committed(&mut self, commit_index: u64) {
+        while let Some(&(last_index, bytes)) = self.uncommitted.front() {
+            if last_index > commit_index {
+                break;
+            }
+            self.uncommitted.pop_front();
+            self.uncommitted_bytes -= bytes;
+        }
+    }
+
+    /// Fails the waiting commands and forgets the uncommitted ones, this server is not the leader anymore.
+    pub(crate) fn clear(&mut self, error: RaftError) {
+        for (_, sender) in self.waiting.drain(..) {
+            let _ = sender.send(Err(error.clone()));
+        }
+        self.uncommitted.clear();
+        self.uncommitted_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::cluster;
+
+    fn command(n: u64) -> Bytes {
+        Bytes::from(n.to_le_bytes().to_vec())
+    }
+
+    #[test]
+    fn in_flight_commands_are_limited() {
+        let mut fixture = cluster();
+        let raft = fixture.raft(0);
+        let limits = Limits::<u64> { max_in_flight: Some(2), ..Limits::default() };
+
+        assert!(unsafe { limits.has_capacity(raft, 0, 0, 8) });
+        // The batched commands are in flight too.
+        assert!(!unsafe { limits.has_capacity(raft, 2, 16, 8) });
+
+        let applied = fixture.apply(0, command(1));
+        assert!(unsafe { limits.has_capacity(raft, 0, 0, 8) });
+        assert!(!unsafe { limits.has_capacity(raft, 1, 8, 8) });
+
+        assert!(fixture.step_until(Duration::from_secs(2), |f| f.fsm(0).0 == 1));
+        assert!(applied.now_or_never().unwrap().is_ok());
+        assert!(unsafe { limits.has_capacity(raft, 1, 8, 8) });
+    }
+
+    #[test]
+    fn uncommitted_bytes_are_limited() {
+        let mut fixture = cluster();
+        let raft = fixture.raft(0);
+        let mut limits = Limits::<u64> { max_uncommitted_bytes: Some(16), ..Limits::default() };
+
+        limits.submitted(10, 6);
+        limits.submitted(11, 6);
+        assert!(unsafe { limits.has_capacity(raft, 0, 0, 4) });
+        assert_eq!(unsafe { limits.reached(raft, 0, 0, 8) }, Some(Limit::UncommittedBytes));
+        assert!(!unsafe { limits.has_capacity(raft, 1, 4, 4) });
+
+        limits.committed(10);
+        assert!(unsafe { limits.has_capacity(raft, 0, 0, 8) });
+
+        // A command bigger than the limit is accepted once everything is committed.
+        assert!(!unsafe { limits.has_capacity(raft, 0, 0, 32) });
+        limits.committed(11);
+        assert!(unsafe { limits.has_capacity(raft, 0, 0, 32) });
+
+        limits.submitted(12, 16);
+        limits.clear(RaftError::NotLeader(None));
+        assert!(unsafe { limits.has_capacity(raft, 0, 0, 16) });
+    }
+
+    #[test]
+    fn waiting_commands_are_drained_in_order() {
+        let mut fixture = cluster();
+        let raft = fixture.raft(0);
+        let mut limits = Limits::<u64> { max_in_flight: Some(1), ..Limits::default() };
+
+        let applied = fixture.apply(0, command(1));
+        let mut receivers = Vec::new();
+        for n in 2..=4 {
+            let (sender, receiver) = oneshot::channel();
+            limits.overflow(command(n), sender, Limit::InFlight);
+            receivers.push(receiver);
+        }
+        assert!(limits.has_waiting());
+        assert!(unsafe { limits.pop_ready(raft, 0, 0) }.is_none());
+
+        assert!(fixture.step_until(Duration::from_secs(2), |f| f.fsm(0).0 == 1));
+        assert!(applied.now_or_never().unwrap().is_ok());
+
+        // The capacity is released for a single command, which is the oldest one.
+        let (first, _) = unsafe { limits.pop_ready(raft, 0, 0) }.unwrap();
+        assert_eq!(first, command(2));
+        assert!(unsafe { limits.pop_ready(raft, 1, 8) }.is_none());
+
+        // The commands that are still waiting fail once the leadership is lost.
+        limits.clear(RaftError::NotLeader(None));
+        assert!(!limits.has_waiting());
+        let mut receivers = receivers.into_iter().map(|receiver| receiver.now_or_never());
+        assert!(matches!(receivers.next(), Some(Some(Err(oneshot::Canceled)))));
+        for result in receivers {
+            assert!(matches!(result, Some(Ok(Err(RaftError::NotLeader(_))))));
+        }
+
+        // Without waiting, the commands past the limits fail immediately.
+        limits.backpressure = Backpressure::Fail;
+        let (sender, receiver) = oneshot::channel();
+        limits.overflow(command(5), sender, Limit::InFlight);
+        assert!(!limits.has_waiting());
+        let in_flight = match receiver.now_or_never() {
+            Some(Ok(Err(RaftError::Busy(Some(message))))) => message,
+            other => panic!("unexpected result {:?}", other),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        limits.overflow(command(6), sender, Limit::UncommittedBytes);
+        match receiver.now_or_never() {
+            Some(Ok(Err(RaftError::Busy(Some(message))))) => assert_ne!(message, in_flight),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,756 @@
+use std::cell::Cell;
+use std::ffi::CString;
+use std::future::Future;
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::time::Duration;
+use std::{mem, ptr};
+
+use bytes::Bytes;
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+use futures_util::future::{self, Either};
+use futures_util::stream::{Stream, StreamExt};
+use libc::{c_int, c_uint, c_void};
+
+use crate::barrier;
+use crate::batch::Batch;
+use crate::change::{Changes, Operation};
+use crate::configuration::Configuration;
+use crate::error::{RaftError, Result};
+use crate::forward::{self, Forward, Waiters};
+use crate::fsm::{Fsm, FsmAdapter};
+use crate::io::Io;
+use crate::join::Joins;
+use crate::lease::Lease;
+use crate::limits::{Backpressure, Limit, Limits};
+use crate::state::{self, Role, State, Status};
+use crate::transfer;
+use crate::watch::{StateChange, Watch};
+#[cfg(any(feature = "tracing", feature = "log"))]
+use crate::tracer::Tracer;
+
+/// An owned raft server, replicating the given `Fsm`.
+///
+/// The raft instance, its I/O backend and its state machine are stored together
+/// on the heap, at an address that never changes until the close sequence completes.
+pub struct Raft<F: Fsm> {
+    inner: NonNull<Inner<F>>,
+}
+
+struct Inner<F: Fsm> {
+    raft: raft,
+    io: Box<dyn Io>,
+    fsm: FsmAdapter<F>,
+    #[cfg(any(feature = "tracing", feature = "log"))]
+    tracer: Option<Tracer>,
+    watch: Watch,
+    changes: Changes,
+    joins: Joins,
+    lease: Lease,
+    forward: Option<Rc<dyn Forward<F::Output>>>,
+    // Wait for the index of the last applied entry and for the time respectively.
+    applied: Waiters,
+    clock: Waiters,
+    batch: Batch<F::Output>,
+    limits: Limits<F::Output>,
+    // Cleared when the handle is dropped, the futures that outlive it must not touch the instance.
+    alive: Rc<Cell<bool>>,
+    // Notified with the state machine once the close sequence completes.
+    closed: Option<oneshot::Sender<F>>,
+}
+
+impl<F: Fsm> Raft<F> {
+    /// Initializes a new raft server, without starting it yet.
+    pub fn new<I: Io + 'static>(io: I, fsm: F, id: u64, address: &str) -> Result<Raft<F>> {
+        let address = match CString::new(address) {
+            Ok(address) => address,
+            Err(_) => return Err(RaftError::Invalid(Some(format!("the address {:?} contains a NUL byte", address)))),
+        };
+
+        let mut inner = Box::new(Inner {
+            raft: unsafe { mem::zeroed() },
+            io: Box::new(io),
+            fsm: FsmAdapter::new(fsm),
+            #[cfg(any(feature = "tracing", feature = "log"))]
+            tracer: None,
+            watch: Watch::new(),
+            changes: Changes::default(),
+            joins: Joins::default(),
+            lease: Lease::default(),
+            forward: None,
+            applied: Waiters::default(),
+            clock: Waiters::default(),
+            batch: Batch::default(),
+            limits: Limits::default(),
+            alive: Rc::new(Cell::new(true)),
+            closed: None,
+        });
+
+        let rv = unsafe {
+            let io = inner.io.as_raw();
+            let fsm = inner.fsm.as_raw();
+            raft_init(&mut inner.raft, io, fsm, id, address.as_ptr())
+        };
+        if rv != 0 {
+            // raft_init releases everything it allocated when it fails.
+            return Err(RaftError::from_errmsg(rv, &inner.raft.errmsg));
+        }
+
+        // The outputs of the batched commands are captured while being applied.
+        let raft = &inner.raft as *const raft;
+        unsafe { (*inner.fsm.captures()).set_raft(raft) };
+
+        // The state is observed each time the I/O backend calls into the raft library.
+        unsafe {
+            let io = inner.io.as_raw();
+            inner.watch.start = (*io).start.take();
+            (*io).start = Some(watch_start::<F>);
+            inner.watch.send = (*io).send.take();
+            (*io).send = Some(watch_send::<F>);
+        }
+
+        let inner = NonNull::new(Box::into_raw(inner)).unwrap();
+        unsafe { (*inner.as_ptr()).raft.data = inner.as_ptr() as *mut c_void };
+
+        Ok(Raft { inner })
+    }
+
+    /// Bootstraps a brand new cluster with the given configuration, this server must be part of it.
+    ///
+    /// This must be done only once, on each server of the initial cluster, and before
+    /// starting it. It fails with `RaftError::CantBootstrap` if this server already has state.
+    pub fn bootstrap(&mut self, configuration: &Configuration) -> Result<()> {
+        // The raft library copies the configuration before storing it.
+        let rv = unsafe { raft_bootstrap(self.as_raw(), configuration.as_ptr()) };
+        if rv != 0 {
+            return Err(unsafe { RaftError::from_raft(rv, self.as_raw()) });
+        }
+
+        Ok(())
+    }
+
+    /// Starts the server, loading its persisted state and starting the I/O backend.
+    pub fn start(&mut self) -> Result<()> {
+        let rv = unsafe { raft_start(self.as_raw()) };
+        if rv != 0 {
+            return Err(unsafe { RaftError::from_raft(rv, self.as_raw()) });
+        }
+
+        Ok(())
+    }
+
+    /// Sets the election timeout, it fails with `RaftError::Invalid` unless
+    /// it is greater than the clock drift given to `set_lease_reads`.
+    pub fn set_election_timeout(&mut self, timeout: Duration) -> Result<()> {
+        unsafe {
+            (*self.inner.as_ptr()).lease.check_election_timeout(timeout)?;
+            raft_set_election_timeout(self.as_raw(), timeout.as_millis() as c_uint);
+        }
+        Ok(())
+    }
+
+    pub fn set_heartbeat_timeout(&mut self, timeout: Duration) {
+        unsafe { raft_set_heartbeat_timeout(self.as_raw(), timeout.as_millis() as c_uint) }
+    }
+
+    /// Sets the number of outstanding log entries that triggers a new snapshot.
+    pub fn set_snapshot_threshold(&mut self, n: u32) {
+        unsafe { raft_set_snapshot_threshold(self.as_raw(), n) }
+    }
+
+    /// Sets the number of log entries to keep in the log after taking a snapshot.
+    pub fn set_snapshot_trailing(&mut self, n: u32) {
+        unsafe { raft_set_snapshot_trailing(self.as_raw(), n) }
+    }
+
+    /// Sets the number of entries a joining server can lag behind the leader
+    /// to be considered caught up, see `join`.
+    pub fn set_catch_up_threshold(&mut self, n: u64) {
+        unsafe { (*self.inner.as_ptr()).joins.threshold = n }
+    }
+
+    /// Sets the time a joining server has to catch up with the leader, see `join`.
+    pub fn set_catch_up_timeout(&mut self, timeout: Duration) {
+        unsafe { (*self.inner.as_ptr()).joins.timeout = timeout }
+    }
+
+    /// Enables the lease reads, see `lease_read`, given the maximum drift between
+    /// the clocks of the servers. It fails with `RaftError::Invalid` unless the drift
+    /// is lower than the election timeout, which must therefore be set before.
+    pub fn set_lease_reads(&mut self, max_clock_drift: Option<Duration>) -> Result<()> {
+        unsafe {
+            let inner = self.inner.as_ptr();
+            (*inner).lease.set_max_drift(&mut (*inner).raft, max_clock_drift)
+        }
+    }
+
+    /// Installs a tracer that forwards the diagnostics of this raft instance.
+    #[cfg(any(feature = "tracing", feature = "log"))]
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        unsafe {
+            let inner = &mut *self.inner.as_ptr();
+            let tracer = inner.tracer.insert(tracer);
+            inner.raft.tracer = tracer.attach(&inner.raft);
+        }
+    }
+
+    /// Proposes a new command to the cluster, this server must be the leader
+    /// unless the requests are forwarded, see `set_forward`.
+    ///
+    /// The returned future resolves to the value returned by `Fsm::apply` once the
+    /// command has been committed and applied, along with the index of its log entry.
+    pub fn apply(&self, command: impl Into<Bytes>) -> impl Future<Output = Result<(F::Output, u64)>> {
+        let command = command.into();
+        let local = unsafe { submit(self.inner.as_ptr(), command.clone()) };
+        let forward = unsafe { (*self.inner.as_ptr()).forward.clone() };
+        let handle = self.handle();
+
+        async move {
+            let mut result = local.await;
+            let forward = match forward {
+                Some(forward) => forward,
+                None => return result,
+            };
+
+            let mut tried = handle.id()?;
+            for _ in 1..forward::MAX_ATTEMPTS {
+                match &result {
+                    Err(error) if forward::can_resend_apply(error) => (),
+                    _ => break,
+                }
+
+                let (id, address) = match handle.leader(tried).await? {
+                    Some(leader) => leader,
+                    None => break,
+                };
+                tried = id;
+                result = if id == handle.id()? {
+                    unsafe { submit(handle.get()?, command.clone()) }.await
+                } else {
+                    forward.apply(id, &address, command.clone()).await
+                };
+            }
+
+            result
+        }
+    }
+
+    /// Limits the number of entries that are not applied yet and the number of bytes that
+    /// are not committed yet, `None` means unlimited, which is the default. The commands
+    /// submitted past these limits either wait or fail with `RaftError::Busy`.
+    pub fn set_apply_limits(
+        &mut self,
+        max_in_flight: Option<usize>,
+        max_uncommitted_bytes: Option<usize>,
+        backpressure: Backpressure,
+    )
+    {
+        unsafe {
+            let inner = self.inner.as_ptr();
+            (*inner).limits.max_in_flight = max_in_flight;
+            (*inner).limits.max_uncommitted_bytes = max_uncommitted_bytes;
+            (*inner).limits.backpressure = backpressure;
+            drain_waiting(inner);
+        }
+    }
+
+    /// Coalesces the commands given to `apply` into a single submission to the raft library.
+    ///
+    /// A batch is submitted once its first command waited for `window`, checked on each
+    /// call to `apply` and each time the I/O backend calls into the raft library, or once it
+    /// reaches `max_bytes`. `None` disables the batching, which is the default.
+    pub fn set_apply_batching(&mut self, window: Option<Duration>, max_bytes: usize) {
+        unsafe {
+            let inner = self.inner.as_ptr();
+            (*inner).batch.window = window;
+            (*inner).batch.max_bytes = max_bytes;
+        }
+        self.flush();
+    }
+
+    /// Submits the batched commands without waiting for the end of the window.
+    pub fn flush(&self) {
+        unsafe {
+            let inner = self.inner.as_ptr();
+            submit_batch(inner);
+        }
+    }
+
+    /// Forwards the requests made on a follower to the leader, sending them again when
+    /// the leadership changes. This concerns `apply` and the reads.
+    ///
+    /// A command is only sent again when it was refused with `RaftError::NotLeader`, it
+    /// then reached no log. When the leadership is lost after it was appended the command
+    /// may still be committed, it fails with `RaftError::LeadershipLost` and sending it
+    /// again is left to the caller, e.g. through a client session, see `session`.
+    pub fn set_forward<W: Forward<F::Output> + 'static>(&mut self, forward: W) {
+        unsafe { (*self.inner.as_ptr()).forward = Some(Rc::new(forward)) }
+    }
+
+    /// Returns the current state of this server.
+    pub fn state(&self) -> State {
+        State::from_raw(unsafe { raft_state(self.as_raw()) })
+    }
+
+    /// Returns a snapshot of the state of this server, its log indexes and the known leader.
+    pub fn status(&self) -> Status {
+        unsafe { Status::from_raw(self.as_raw()) }
+    }
+
+    /// Waits until every entry committed before this call is applied on this server,
+    /// which must be the leader, and resolves to the index of the last applied entry.
+    ///
+    /// The state machine can then be read without missing any acknowledged write.
+    /// It fails with `RaftError::NotLeader`, naming the leader if known, on the other servers.
+    pub fn linearizable_read(&self) -> impl Future<Output = Result<u64>> {
+        unsafe { barrier::barrier(self.as_raw(), |index| index) }
+    }
+
+    /// Runs the given closure against the state machine once every entry committed
+    /// before this call is applied on this server, which must be the leader.
+    ///
+    /// It fails with `RaftError::NotLeader`, naming the leader if known, on the other servers.
+    /// When the requests are forwarded the closure runs on this server once it has applied
+    /// every entry the leader committed before the call, see `set_forward`.
+    pub fn read<R, G>(&self, read: G) -> impl Future<Output = Result<R>>
+    where G: FnOnce(&F) -> R + 'static,
+    {
+        let inner = self.inner.as_ptr();
+        let forward = match unsafe { (*inner).forward.clone() } {
+            Some(forward) => forward,
+            None => unsafe {
+                // The barrier callback is invoked before the close callback releases the state machine.
+                let read = barrier::barrier(&mut (*inner).raft, move |_| read((*inner).fsm.fsm()));
+                return Either::Left(read);
+            },
+        };
+
+        let local = self.linearizable_read();
+        let handle = self.handle();
+
+        Either::Right(async move {
+            let mut result = local.await;
+            let mut tried = handle.id()?;
+            for _ in 1..forward::MAX_ATTEMPTS {
+                match &result {
+                    Err(error) if forward::can_resend_read(error) => (),
+                    _ => break,
+                }
+
+                let (id, address) = match handle.leader(tried).await? {
+                    Some(leader) => leader,
+                    None => break,
+                };
+                tried = id;
+                result = if id == handle.id()? {
+                    unsafe { barrier::barrier(&mut (*handle.get()?).raft, |index| index) }.await
+                } else {
+                    forward.read_index(id, &address).await
+                };
+            }
+
+            // The index committed by a remote leader may not be applied here yet.
+            let index = result?;
+            let applied = unsafe {
+                let inner = handle.get()?;
+                (*inner).applied.wait(index, (*inner).raft.last_applied)
+            };
+            applied.await.map_err(|_| RaftError::Shutdown(None))?;
+
+            let inner = handle.get()?;
+            Ok(read(unsafe { (*inner).fsm.fsm() }))
+        })
+    }
+
+    /// Runs the given closure against the state machine of this server, which must be the leader.
+    ///
+    /// While the lease of the leader is valid the closure runs immediately, otherwise,
+    /// or if the lease reads are disabled, this behaves like `read`. The lease relies on
+    /// the clocks of the servers not drifting more than configured by `set_lease_reads`.
+    pub fn lease_read<R, G>(&self, read: G) -> impl Future<Output = Result<R>>
+    where G: FnOnce(&F) -> R + 'static,
+    {
+        let inner = self.inner.as_ptr();
+        if unsafe { (*inner).lease.is_valid(&mut (*inner).raft) } {
+            let output = read(unsafe { (*inner).fsm.fsm() });
+            Either::Left(future::ready(Ok(output)))
+        } else {
+            Either::Right(self.read(read))
+        }
+    }
+
+    /// Adds a new server to the cluster configuration, as a spare, this server must be the leader.
+    ///
+    /// The membership changes are submitted one after the other, the future resolves
+    /// once this one has been committed.
+    pub fn add_server(&self, id: u64, address: &str) -> impl Future<Output = Result<()>> {
+        self.change(Operation::Add(id, address.to_owned()))
+    }
+
+    /// Assigns a new role to a server of the cluster configuration, this server must be the leader.
+    ///
+    /// When promoting a server to voter the future resolves once it has caught up
+    /// with the log and the new configuration has been committed.
+    pub fn assign_role(&self, id: u64, role: Role) -> impl Future<Output = Result<()>> {
+        self.change(Operation::Assign(id, role))
+    }
+
+    /// Adds a new server to the cluster and gives it the target role once it has caught up
+    /// with the log of this server, which must be the leader.
+    ///
+    /// Unless the target role is spare, the server is added as a standby so that it receives
+    /// the log, it is then considered caught up when it lags less than the catch-up threshold
+    /// behind. If it does not catch up before the catch-up timeout the future fails with
+    /// `RaftError::Timeout`, the server stays a standby.
+    pub fn join(&self, id: u64, address: &str, role: Role) -> impl Future<Output = Result<()>> {
+        unsafe {
+            let inner = self.inner.as_ptr();
+            (*inner).joins.push(&mut (*inner).changes, &mut (*inner).raft, id, address, role)
+        }
+    }
+
+    /// Removes a server from the cluster configuration, this server must be the leader.
+    pub fn remove_server(&self, id: u64) -> impl Future<Output = Result<()>> {
+        self.change(Operation::Remove(id))
+    }
+
+    fn change(&self, operation: Operation) -> impl Future<Output = Result<()>> {
+        unsafe {
+            let inner = self.inner.as_ptr();
+            Changes::push(&mut (*inner).changes, &mut (*inner).raft, operation)
+        }
+    }
+
+    /// Transfers the leadership to the given voter, this server must be the leader.
+    ///
+    /// Without an id the voter whose log matches the most entries is chosen. The future
+    /// resolves to the id and address of the new leader, or fails with `RaftError::Timeout`
+    /// if the target did not take the leadership before an election timeout.
+    pub fn transfer_leadership(&self, id: Option<u64>) -> impl Future<Output = Result<(u64, String)>> {
+        unsafe { transfer::transfer(self.as_raw(), id) }
+    }
+
+    /// Returns a stream of the transitions of this server, along with the changes of leader.
+    ///
+    /// The transitions are observed after each tick and each received message,
+    /// the stream ends once the server is closed.
+    pub fn subscribe(&self) -> impl Stream<Item = StateChange> {
+        unsafe { (*self.inner.as_ptr()).watch.subscribe() }
+    }
+
+    fn handle(&self) -> Handle<F> {
+        let alive = unsafe { Rc::clone(&(*self.inner.as_ptr()).alive) };
+        Handle { inner: self.inner.as_ptr(), alive }
+    }
+
+    /// Returns the underlying raft instance, for the functions that are not wrapped yet.
+    pub fn as_raw(&self) -> *mut raft {
+        unsafe { &mut (*self.inner.as_ptr()).raft }
+    }
+
+    /// Closes the server, the returned future resolves to the state machine
+    /// once every pending request has completed and the I/O backend is closed.
+    ///
+    /// Note that the close sequence only progresses while the event loop runs.
+    pub fn close(self) -> impl Future<Output = F> {
+        let (sender, receiver) = oneshot::channel();
+        unsafe { (*self.inner.as_ptr()).closed = Some(sender) };
+
+        // Dropping the handle starts the close sequence.
+        drop(self);
+
+        async move { receiver.await.expect("the close callback never drops the sender") }
+    }
+}
+
+impl<F: Fsm> Drop for Raft<F> {
+    fn drop(&mut self) {
+        // The memory is released by the close callback, the raft library
+        // keeps using it until then.
+        unsafe {
+            (*self.inner.as_ptr()).alive.set(false);
+            (*self.inner.as_ptr()).changes.close();
+            raft_close(self.as_raw(), Some(raft_close_cb::<F>))
+        }
+    }
+}
+
+/// A pointer to the raft instance that can be kept across await points,
+/// it can only be used while the `Raft` handle is alive.
+struct Handle<F: Fsm> {
+    inner: *mut Inner<F>,
+    alive: Rc<Cell<bool>>,
+}
+
+impl<F: Fsm> Handle<F> {
+    fn get(&self) -> Result<*mut Inner<F>> {
+        if self.alive.get() { Ok(self.inner) } else { Err(RaftError::Shutdown(None)) }
+    }
+
+    fn id(&self) -> Result<u64> {
+        Ok(unsafe { (*self.get()?).raft.id })
+    }
+
+    /// Waits for a leader other than the one that was tried, for an election timeout at most,
+    /// after which the same leader is tried again. `None` if no leader is known by then.
+    async fn leader(&self, tried: u64) -> Result<Option<(u64, String)>> {
+        let deadline = unsafe {
+            let raft: *mut raft = &mut (*self.get()?).raft;
+            state::now(raft).unwrap_or(0) + (*raft).election_timeout as raft_time
+        };
+
+        let mut changes = unsafe { (*self.get()?).watch.subscribe() };
+        loop {
+            let (leader, now) = unsafe {
+                let raft: *mut raft = &mut (*self.get()?).raft;
+                (Status::from_raw(raft).leader, state::now(raft).unwrap_or(deadline))
+            };
+            match leader {
+                Some((id, address)) if id != tried => return Ok(Some((id, address))),
+                leader if now >= deadline => return Ok(leader),
+                _ => (),
+            }
+
+            // Either the leadership changes or the time passes, which the raft library observes on each tick.
+            let elapsed = unsafe { (*self.get()?).clock.wait(deadline, now) };
+            if let Either::Left((None, _)) = future::select(changes.next(), elapsed).await {
+                // The stream ends when the server is closed.
+                return Err(RaftError::Shutdown(None));
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn raft_close_cb<F: Fsm>(raft: *mut raft) {
+    let inner = Box::from_raw((*raft).data as *mut Inner<F>);
+    let Inner { io, fsm, closed, .. } = *inner;
+
+    // The raft instance is closed, we can now release the I/O backend.
+    drop(io);
+
+    if let Some(sender) = closed {
+        let _ = sender.send(fsm.into_inner());
+    }
+}
+
+unsafe fn inner_of<F: Fsm>(io: *mut raft_io) -> *mut Inner<F> {
+    // The raft library stores itself in the data of its I/O backend.
+    let raft = (*io).data as *mut raft;
+    (*raft).data as *mut Inner<F>
+}
+
+unsafe extern "C" fn watch_start<F: Fsm>(
+    io: *mut raft_io,
+    msecs: c_uint,
+    tick: raft_io_tick_cb,
+    recv: raft_io_recv_cb,
+) -> c_int
+{
+    let inner = inner_of::<F>(io);
+    (*inner).watch.tick = tick;
+    (*inner).watch.recv = recv;
+
+    match (*inner).watch.start {
+        Some(start) => start(io, msecs, Some(watch_tick::<F>), Some(watch_recv::<F>)),
+        None => RaftError::Invalid(None).code(),
+    }
+}
+
+/// Runs after each call into the raft library made by the I/O backend.
+unsafe fn observe<F: Fsm>(inner: *mut Inner<F>) {
+    (*inner).watch.observe(&mut (*inner).raft);
+    (*inner).joins.check(&mut (*inner).changes, &mut (*inner).raft);
+    (*inner).lease.observe(&mut (*inner).raft);
+    (*inner).applied.observe((*inner).raft.last_applied);
+    if let Some(now) = state::now(&mut (*inner).raft) {
+        (*inner).clock.observe(now);
+    }
+
+    if State::from_raw(raft_state(&mut (*inner).raft)) == State::Leader {
+        (*inner).limits.committed((*inner).raft.commit_index);
+        drain_waiting(inner);
+    } else {
+        (*inner).limits.clear(RaftError::NotLeader(None));
+        (*(*inner).fsm.captures()).clear(RaftError::LeadershipLost(None));
+    }
+
+    if (*inner).batch.is_due(&mut (*inner).raft) {
+        submit_batch(inner);
+    }
+}
+
+unsafe extern "C" fn watch_tick<F: Fsm>(io: *mut raft_io) {
+    let inner = inner_of::<F>(io);
+    if let Some(tick) = (*inner).watch.tick {
+        tick(io);
+    }
+    observe(inner);
+}
+
+unsafe extern "C" fn watch_recv<F: Fsm>(io: *mut raft_io, message: *mut raft_message) {
+    let inner = inner_of::<F>(io);
+    (*inner).lease.received(&mut (*inner).raft, message);
+    if let Some(recv) = (*inner).watch.recv {
+        recv(io, message);
+    }
+    observe(inner);
+}
+
+unsafe extern "C" fn watch_send<F: Fsm>(
+    io: *mut raft_io,
+    req: *mut raft_io_send,
+    message: *const raft_message,
+    cb: raft_io_send_cb,
+) -> c_int
+{
+    let inner = inner_of::<F>(io);
+    let rv = match (*inner).watch.send {
+        Some(send) => send(io, req, message, cb),
+        None => return RaftError::Invalid(None).code(),
+    };
+
+    if rv == 0 {
+        (*inner).lease.sent(&mut (*inner).raft, message);
+    }
+
+    rv
+}
+
+/// Proposes a command to the given raft instance, whose state machine outputs `T`.
+pub(crate) unsafe fn apply<T>(raft: *mut raft, command: Bytes) -> impl Future<Output = Result<(T, u64)>> {
+    let (sender, receiver) = oneshot::channel();
+    submit_apply(raft, command, sender);
+
+    async move {
+        match receiver.await {
+            Ok(result) => result,
+            Err(oneshot::Canceled) => Err(RaftError::Canceled(None)),
+        }
+    }
+}
+
+/// Copies a command into a buffer that can be given to `raft_apply`, `None` if out of memory.
+///
+/// The raft library releases the entries with raft_free,
+/// they must therefore be allocated with raft_malloc.
+pub(crate) unsafe fn command_buf(command: &[u8]) -> Option<raft_buffer> {
+    let base = raft_malloc(command.len().max(1));
+    if base.is_null() {
+        return None;
+    }
+    ptr::copy_nonoverlapping(command.as_ptr(), base as *mut u8, command.len());
+    Some(raft_buffer { base, len: command.len() })
+}
+
+/// Submits a single command, the outcome is sent to the sender, even if the submission fails.
+unsafe fn submit_apply<T>(raft: *mut raft, command: Bytes, sender: oneshot::Sender<Result<(T, u64)>>) {
+    let buf = match command_buf(&command) {
+        Some(buf) => buf,
+        None => {
+            let _ = sender.send(Err(RaftError::NoMem(None)));
+            return;
+        },
+    };
+
+    let req = Box::into_raw(Box::new(ApplyRequest { raw: mem::zeroed(), sender }));
+    (*req).raw.data = req as *mut c_void;
+
+    let rv = raft_apply(raft, &mut (*req).raw, &buf, 1, Some(apply_cb::<T>));
+    if rv != 0 {
+        let error = RaftError::from_raft(rv, raft);
+        // The raft library only takes the ownership of the buffer when it succeeds.
+        raft_free(buf.base);
+        let req = Box::from_raw(req);
+        let _ = req.sender.send(Err(error));
+    }
+}
+
+/// Submits a command to the raft library, or to the apply batch if enabled.
+unsafe fn dispatch<F: Fsm>(inner: *mut Inner<F>, command: Bytes, sender: oneshot::Sender<Result<(F::Output, u64)>>) {
+    if (*inner).batch.window.is_none() {
+        let last_index = raft_last_index(&mut (*inner).raft);
+        let len = command.len();
+        submit_apply(&mut (*inner).raft, command, sender);
+
+        let new_last_index = raft_last_index(&mut (*inner).raft);
+        if new_last_index > last_index {
+            (*inner).limits.submitted(new_last_index, len);
+        }
+    } else if (*inner).batch.push(&mut (*inner).raft, command, sender) {
+        submit_batch(inner);
+    }
+}
+
+/// Submits the batched commands, their bytes count as uncommitted once they reach the log.
+unsafe fn submit_batch<F: Fsm>(inner: *mut Inner<F>) {
+    let last_index = raft_last_index(&mut (*inner).raft);
+    let bytes = (*inner).batch.bytes();
+    (*inner).batch.submit(&mut (*inner).raft, (*inner).fsm.captures());
+
+    let new_last_index = raft_last_index(&mut (*inner).raft);
+    if new_last_index > last_index {
+        (*inner).limits.submitted(new_last_index, bytes);
+    }
+}
+
+/// Submits a command through the limits and the apply batch.
+unsafe fn submit<F: Fsm>(inner: *mut Inner<F>, command: Bytes) -> impl Future<Output = Result<(F::Output, u64)>> {
+    let (sender, receiver) = oneshot::channel();
+
+    // The limits only concern the leader, the others fail with NotLeader.
+    let is_leader = State::from_raw(raft_state(&mut (*inner).raft)) == State::Leader;
+    let reached = if is_leader { limit_reached(inner, command.len()) } else { None };
+    if is_leader && (*inner).limits.has_waiting() {
+        // The command must not overtake the ones already waiting for capacity.
+        (*inner).limits.wait(command, sender);
+    } else if let Some(limit) = reached {
+        (*inner).limits.overflow(command, sender, limit);
+    } else {
+        dispatch(inner, command, sender);
+    }
+
+    async move {
+        match receiver.await {
+            Ok(result) => result,
+            Err(oneshot::Canceled) => Err(RaftError::Canceled(None)),
+        }
+    }
+}
+
+unsafe fn limit_reached<F: Fsm>(inner: *mut Inner<F>, len: usize) -> Option<Limit> {
+    let batch = &(*inner).batch;
+    (*inner).limits.reached(&mut (*inner).raft, batch.len(), batch.bytes(), len)
+}
+
+/// Submits the commands that waited for capacity, in order.
+unsafe fn drain_waiting<F: Fsm>(inner: *mut Inner<F>) {
+    loop {
+        let batch = &(*inner).batch;
+        match (*inner).limits.pop_ready(&mut (*inner).raft, batch.len(), batch.bytes()) {
+            Some((command, sender)) => dispatch(inner, command, sender),
+            None => break,
+        }
+    }
+}
+
+struct ApplyRequest<T> {
+    raw: raft_apply,
+    sender: oneshot::Sender<Result<(T, u64)>>,
+}
+
+unsafe extern "C" fn apply_cb<T>(req: *mut raft_apply, status: c_int, result: *mut c_void) {
+    let req = Box::from_raw((*req).data as *mut ApplyRequest<T>);
+
+    let result = if status == 0 {
+        // The result points to the output slot of the FsmAdapter.
+        match (result as *mut Option<T>).as_mut().and_then(Option::take) {
+            Some(output) => Ok((output, req.raw.index)),
+            None => Err(RaftError::Canceled(None)),
+        }
+    } else {
+        Err(RaftError::from_code(status))
+    };
+
+    let _ = req.sender.send(result);
+}
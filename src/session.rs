@@ -0,0 +1,424 @@
+//! Exactly-once client sessions, the commands that are retried are only applied once.
+//!
+//! Every command carries the id of the client session and a sequence number, the
+//! `Sessions` state machine remembers the last sequence number and output of each
+//! session and returns this output again when a command is applied twice. The
+//! sessions are part of the replicated state and therefore of the snapshots.
+//!
+//! The sessions are expired by dedicated entries carrying the time of the leader
+//! that submitted them, see `expire_command`, the clocks of the clients are never used.
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::error::{RaftError, Result};
+use crate::fsm::Fsm;
+
+/// The version of the snapshot format of the sessions.
+const SNAPSHOT_FORMAT: u8 = 2;
+
+const PLAIN: u8 = 0;
+const COMMAND: u8 = 1;
+const CLOSE: u8 = 2;
+const EXPIRE: u8 = 3;
+
+/// Encodes the outputs of a state machine, the sessions keep the
+/// last output of each client in the snapshots.
+pub trait OutputCodec: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+impl OutputCodec for () {
+    fn encode(&self, _buf: &mut Vec<u8>) {}
+
+    fn decode(_bytes: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputCodec for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<u64> {
+        let bytes = bytes.try_into().map_err(|_| RaftError::Malformed(None))?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl OutputCodec for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Wraps a state machine to apply the commands of the client sessions exactly once.
+///
+/// The output of a command is `Err` when it was rejected by the session layer, because
+/// its session expired or it is older than the last command of its session, and `None`
+/// for the entries of the session layer itself. The expiry is driven by the times carried
+/// by the expiry entries, the time-to-live must therefore be the same on every server.
+pub struct Sessions<F: Fsm> {
+    fsm: F,
+    ttl: u64,
+    // The highest time carried by the applied expiry entries, in milliseconds.
+    clock: u64,
+    sessions: HashMap<u64, Session<F::Output>>,
+    // The sessions ordered by the clock of their last command, the oldest first.
+    by_last_seen: BTreeSet<(u64, u64)>,
+}
+
+struct Session<T> {
+    last_sequence: u64,
+    last_output: Option<T>,
+    last_seen: u64,
+}
+
+impl<F: Fsm> Sessions<F>
+where F::Output: OutputCodec + Clone,
+{
+    /// Wraps the given state machine, the sessions inactive for `ttl` are expired.
+    pub fn new(fsm: F, ttl: Duration) -> Sessions<F> {
+        Sessions {
+            fsm,
+            ttl: ttl.as_millis() as u64,
+            clock: 0,
+            sessions: HashMap::new(),
+            by_last_seen: BTreeSet::new(),
+        }
+    }
+
+    pub fn fsm(&self) -> &F {
+        &self.fsm
+    }
+
+    pub fn fsm_mut(&mut self) -> &mut F {
+        &mut self.fsm
+    }
+
+    pub fn into_inner(self) -> F {
+        self.fsm
+    }
+
+    /// Returns the number of sessions that are not expired.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Advances the clock and removes the sessions inactive for longer than the time-to-live.
+    fn expire(&mut self, time: u64) {
+        self.clock = self.clock.max(time);
+        while let Some(&(last_seen, client)) = self.by_last_seen.iter().next() {
+            if last_seen.saturating_add(self.ttl) >= self.clock {
+                break;
+            }
+            self.by_last_seen.remove(&(last_seen, client));
+            self.sessions.remove(&client);
+        }
+    }
+
+    fn apply_command(&mut self, client: u64, sequence: u64, payload: &[u8]) -> Result<Result<Option<F::Output>>> {
+        let clock = self.clock;
+        let session = match self.sessions.get_mut(&client) {
+            Some(session) => session,
+            // A session starts with its first command, the others are retries of an expired session.
+            None if sequence == 1 => {
+                let session = Session { last_sequence: 0, last_output: None, last_seen: clock };
+                self.by_last_seen.insert((clock, client));
+                self.sessions.entry(client).or_insert(session)
+            },
+            None => return Ok(Err(RaftError::NotFound(Some(format!("session {} expired", client))))),
+        };
+
+        if session.last_seen != clock {
+            self.by_last_seen.remove(&(session.last_seen, client));
+            self.by_last_seen.insert((clock, client));
+            session.last_seen = clock;
+        }
+
+        if sequence < session.last_sequence {
+            let message = format!("command {} of session {} is outdated", sequence, client);
+            return Ok(Err(RaftError::Invalid(Some(message))));
+        }
+
+        if sequence == session.last_sequence {
+            return match &session.last_output {
+                Some(output) => Ok(Ok(Some(output.clone()))),
+                None => Ok(Err(RaftError::NotFound(Some(format!("session {} expired", client))))),
+            };
+        }
+
+        let output = self.fsm.apply(payload)?;
+        session.last_sequence = sequence;
+        session.last_output = Some(output.clone());
+
+        Ok(Ok(Some(output)))
+    }
+}
+
+impl<F: Fsm> Fsm for Sessions<F>
+where F::Output: OutputCodec + Clone,
+{
+    type Output = Result<Option<F::Output>>;
+
+    fn apply(&mut self, command: &[u8]) -> Result<Self::Output> {
+        let mut cursor = command;
+        match take(&mut cursor, 1)?[0] {
+            PLAIN => self.fsm.apply(cursor).map(|output| Ok(Some(output))),
+            COMMAND => {
+                let client = take_u64(&mut cursor)?;
+                let sequence = take_u64(&mut cursor)?;
+                self.apply_command(client, sequence, cursor)
+            },
+            CLOSE => {
+                let client = take_u64(&mut cursor)?;
+                match self.sessions.remove(&client) {
+                    Some(session) => {
+                        self.by_last_seen.remove(&(session.last_seen, client));
+                        Ok(Ok(None))
+                    },
+                    None => Ok(Err(RaftError::NotFound(Some(format!("session {} expired", client))))),
+                }
+            },
+            EXPIRE => {
+                let time = take_u64(&mut cursor)?;
+                self.expire(time);
+                Ok(Ok(None))
+            },
+            _ => Err(RaftError::Malformed(None)),
+        }
+    }
+
+    fn snapshot<W: Write>(&self, mut writer: W) -> Result<()> {
+        // The sessions come first, the inner snapshot is streamed until the end.
+        writer.write_all(&[SNAPSHOT_FORMAT])?;
+        writer.write_all(&self.clock.to_le_bytes())?;
+        writer.write_all(&(self.sessions.len() as u64).to_le_bytes())?;
+
+        let mut output = Vec::new();
+        for (client, session) in &self.sessions {
+            writer.write_all(&client.to_le_bytes())?;
+            writer.write_all(&session.last_sequence.to_le_bytes())?;
+            writer.write_all(&session.last_seen.to_le_bytes())?;
+            match &session.last_output {
+                Some(last_output) => {
+                    output.clear();
+                    last_output.encode(&mut output);
+                    writer.write_all(&[1])?;
+                    writer.write_all(&(output.len() as u64).to_le_bytes())?;
+                    writer.write_all(&output)?;
+                },
+                None => writer.write_all(&[0])?,
+            }
+        }
+
+        self.fsm.snapshot(writer)
+    }
+
+    fn restore<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        if read_u8(&mut reader)? != SNAPSHOT_FORMAT {
+            return Err(RaftError::Malformed(None));
+        }
+
+        let clock = read_u64(&mut reader)?;
+
+        let n = read_u64(&mut reader)?;
+        let mut sessions = HashMap::new();
+        let mut by_last_seen = BTreeSet::new();
+        for _ in 0..n {
+            let client = read_u64(&mut reader)?;
+            let last_sequence = read_u64(&mut reader)?;
+            let last_seen = read_u64(&mut reader)?;
+            let last_output = match read_u8(&mut reader)? {
+                0 => None,
+                _ => {
+                    let len = read_u64(&mut reader)?;
+                    let mut output = Vec::new();
+                    reader.by_ref().take(len).read_to_end(&mut output)?;
+                    if output.len() as u64 != len {
+                        return Err(RaftError::Malformed(None));
+                    }
+                    Some(F::Output::decode(&output)?)
+                },
+            };
+            sessions.insert(client, Session { last_sequence, last_output, last_seen });
+            by_last_seen.insert((last_seen, client));
+        }
+
+        // The sessions are only replaced once the whole snapshot is decoded.
+        self.fsm.restore(reader)?;
+        self.clock = clock;
+        self.sessions = sessions;
+        self.by_last_seen = by_last_seen;
+
+        Ok(())
+    }
+}
+
+/// The client side of a session, it wraps the commands given to `Raft::apply`.
+///
+/// A command that failed must be retried with the exact same bytes, it is then
+/// only applied once. The id must be unique across the clients, e.g. random.
+#[derive(Debug)]
+pub struct ClientSession {
+    id: u64,
+    next_sequence: u64,
+}
+
+impl ClientSession {
+    pub fn new(id: u64) -> ClientSession {
+        ClientSession { id, next_sequence: 1 }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wraps the next command of this session.
+    pub fn command(&mut self, payload: &[u8]) -> Bytes {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let mut buf = BytesMut::with_capacity(1 + 2 * 8 + payload.len());
+        buf.put_u8(COMMAND);
+        buf.put_u64_le(self.id);
+        buf.put_u64_le(sequence);
+        buf.put_slice(payload);
+        buf.freeze()
+    }
+
+    /// Returns the command that closes this session, its output is `Ok(None)`.
+    pub fn close(self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(1 + 8);
+        buf.put_u8(CLOSE);
+        buf.put_u64_le(self.id);
+        buf.freeze()
+    }
+}
+
+/// Wraps a command that is not part of any session, it is applied each time it is submitted.
+pub fn plain_command(payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + payload.len());
+    buf.put_u8(PLAIN);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Returns the entry that expires the inactive sessions, stamped with the current time.
+///
+/// It must be submitted periodically by the leader, e.g. every half time-to-live, the
+/// clock of the sessions only advances with these entries. Its output is `Ok(None)`.
+pub fn expire_command() -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + 8);
+    buf.put_u8(EXPIRE);
+    buf.put_u64_le(now());
+    buf.freeze()
+}
+
+fn now() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_millis() as u64
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn take<'a>(cursor: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if cursor.len() < n {
+        return Err(RaftError::Malformed(None));
+    }
+    let (head, tail) = cursor.split_at(n);
+    *cursor = tail;
+    Ok(head)
+}
+
+fn take_u64(cursor: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(take(cursor, 8)?.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::Counter;
+
+    #[test]
+    fn duplicates_are_applied_once() {
+        let mut sessions = Sessions::new(Counter(0), Duration::from_secs(60));
+        let mut client = ClientSession::new(42);
+
+        let first = client.command(&1u64.to_le_bytes());
+        assert_eq!(sessions.apply(&first).unwrap(), Ok(Some(1)));
+        assert_eq!(sessions.apply(&first).unwrap(), Ok(Some(1)));
+
+        let second = client.command(&2u64.to_le_bytes());
+        assert_eq!(sessions.apply(&second).unwrap(), Ok(Some(3)));
+        assert!(sessions.apply(&first).unwrap().is_err());
+
+        let mut snapshot = Vec::new();
+        sessions.snapshot(&mut snapshot).unwrap();
+        let mut restored = Sessions::new(Counter(0), Duration::from_secs(60));
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.apply(&second).unwrap(), Ok(Some(3)));
+        assert_eq!(restored.fsm().0, 3);
+
+        assert_eq!(restored.apply(&client.close()).unwrap(), Ok(None));
+        assert!(restored.is_empty());
+    }
+
+    fn expire_at(time: u64) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(EXPIRE);
+        buf.put_u64_le(time);
+        buf.freeze()
+    }
+
+    #[test]
+    fn sessions_expire_with_the_clock_of_the_expiry_entries() {
+        let mut sessions = Sessions::new(Counter(0), Duration::from_millis(100));
+        let mut idle = ClientSession::new(1);
+        let mut active = ClientSession::new(2);
+
+        assert_eq!(sessions.apply(&expire_at(1000)).unwrap(), Ok(None));
+        assert_eq!(sessions.apply(&idle.command(&1u64.to_le_bytes())).unwrap(), Ok(Some(1)));
+        assert_eq!(sessions.apply(&active.command(&1u64.to_le_bytes())).unwrap(), Ok(Some(2)));
+
+        assert_eq!(sessions.apply(&expire_at(1080)).unwrap(), Ok(None));
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.apply(&active.command(&1u64.to_le_bytes())).unwrap(), Ok(Some(3)));
+
+        // An older time never moves the clock backwards.
+        assert_eq!(sessions.apply(&expire_at(500)).unwrap(), Ok(None));
+        assert_eq!(sessions.apply(&expire_at(1150)).unwrap(), Ok(None));
+        assert_eq!(sessions.len(), 1);
+
+        let retried = idle.command(&1u64.to_le_bytes());
+        assert!(matches!(sessions.apply(&retried).unwrap(), Err(RaftError::NotFound(_))));
+        assert!(matches!(sessions.apply(&idle.close()).unwrap(), Err(RaftError::NotFound(_))));
+        assert_eq!(sessions.apply(&active.close()).unwrap(), Ok(None));
+        assert!(sessions.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use std::ffi::CStr;
+use std::{ptr, slice};
+
+use canonical_raft_sys::*;
+use libc::{c_char, c_int};
+
+use crate::error::{RaftError, Result};
+
+/// The state of a raft server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Unavailable,
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl State {
+    pub(crate) fn from_raw(state: c_int) -> State {
+        match state as u32 {
+            RAFT_FOLLOWER => State::Follower,
+            RAFT_CANDIDATE => State::Candidate,
+            RAFT_LEADER => State::Leader,
+            _ => State::Unavailable,
+        }
+    }
+
+    pub(crate) fn to_raw(self) -> c_int {
+        let state = match self {
+            State::Unavailable => RAFT_UNAVAILABLE,
+            State::Follower => RAFT_FOLLOWER,
+            State::Candidate => RAFT_CANDIDATE,
+            State::Leader => RAFT_LEADER,
+        };
+        state as c_int
+    }
+}
+
+/// The role of a server in the cluster configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Replicates the log and takes part in the elections and the quorum.
+    Voter,
+    /// Replicates the log but does not take part in the quorum.
+    Standby,
+    /// Does not replicate the log.
+    Spare,
+}
+
+impl Role {
+    pub(crate) fn from_raw(role: c_int) -> Result<Role> {
+        match role {
+            RAFT_VOTER => Ok(Role::Voter),
+            RAFT_STANDBY => Ok(Role::Standby),
+            RAFT_SPARE => Ok(Role::Spare),
+            _ => Err(RaftError::BadRole(None)),
+        }
+    }
+
+    pub(crate) fn to_raw(self) -> c_int {
+        match self {
+            Role::Voter => RAFT_VOTER,
+            Role::Standby => RAFT_STANDBY,
+            Role::Spare => RAFT_SPARE,
+        }
+    }
+}
+
+/// A snapshot of the state of a raft server, as returned by `Raft::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub id: u64,
+    pub address: String,
+    pub state: State,
+    pub current_term: u64,
+    /// The server this one voted for in the current term, if any.
+    pub voted_for: Option<u64>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub last_stored: u64,
+    /// The id and address of the current leader, if known.
+    pub leader: Option<(u64, String)>,
+    /// The index of the last committed configuration.
+    pub configuration_index: u64,
+}
+
+impl Status {
+    pub(crate) unsafe fn from_raw(raft: *mut raft) -> Status {
+        let mut leader_id = 0;
+        let mut leader_address: *const c_char = ptr::null();
+        raft_leader(raft, &mut leader_id, &mut leader_address);
+
+        let leader = if leader_id != 0 && !leader_address.is_null() {
+            Some((leader_id, string(leader_address)))
+        } else {
+            None
+        };
+
+        let r = &*raft;
+        Status {
+            id: r.id,
+            address: string(r.address),
+            state: State::from_raw(raft_state(raft)),
+            current_term: r.current_term,
+            voted_for: if r.voted_for == 0 { None } else { Some(r.voted_for) },
+            commit_index: r.commit_index,
+            last_applied: r.last_applied,
+            last_stored: r.last_stored,
+            leader,
+            configuration_index: r.configuration_index,
+        }
+    }
+}
+
+/// Returns the current time of the raft instance in milliseconds, if its io can tell it.
+pub(crate) unsafe fn now(raft: *mut raft) -> Option<raft_time> {
+    let io = (*raft).io;
+    (*io).time.map(|time| time(io))
+}
+
+/// Iterates over the servers of the configuration along with the progress of their replication.
+///
+/// The raft instance must be the leader, it only tracks the progress while leading.
+pub(crate) unsafe fn progress<'a>(raft: *mut raft) -> impl Iterator<Item = (&'a raft_server, &'a raft_progress)> {
+    // The progress of the followers is stored in the order of the configuration.
+    let configuration = &(*raft).configuration;
+    let servers = slice::from_raw_parts(configuration.servers, configuration.n as usize);
+    let progress = slice::from_raw_parts((*raft).__bindgen_anon_1.leader_state.progress, servers.len());
+    servers.iter().zip(progress)
+}
+
+unsafe fn string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(s).to_string_lossy().into_owned()
+}
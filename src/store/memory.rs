@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+
+use crate::error::Result;
+use super::{Log, LogStore, SnapshotData, SnapshotMeta, SnapshotStore, StableStore};
+
+/// A store that keeps everything in memory, the state is lost when it is dropped.
+///
+/// It is useful for tests and for servers that can always recover their state from the cluster.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    logs: BTreeMap<u64, Log>,
+    stable: HashMap<Vec<u8>, Vec<u8>>,
+    // The snapshot is kept in the chunks it was given in.
+    snapshot: Option<(SnapshotMeta, Vec<Vec<u8>>)>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl LogStore for MemoryStore {
+    fn first_index(&self) -> Result<u64> {
+        Ok(self.logs.keys().next().copied().unwrap_or(0))
+    }
+
+    fn last_index(&self) -> Result<u64> {
+        Ok(self.logs.keys().next_back().copied().unwrap_or(0))
+    }
+
+    fn get_log(&self, index: u64) -> Result<Option<Log>> {
+        Ok(self.logs.get(&index).cloned())
+    }
+
+    fn store_logs(&mut self, logs: &[Log]) -> Result<()> {
+        for log in logs {
+            self.logs.insert(log.index, log.clone());
+        }
+        Ok(())
+    }
+
+    fn delete_range(&mut self, min: u64, max: u64) -> Result<()> {
+        if min <= max {
+            let indexes: Vec<_> = self.logs.range(min..=max).map(|(i, _)| *i).collect();
+            for index in indexes {
+                self.logs.remove(&index);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StableStore for MemoryStore {
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.stable.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.stable.get(key).cloned())
+    }
+}
+
+impl SnapshotStore for MemoryStore {
+    fn create(&mut self, meta: SnapshotMeta, chunks: &[&[u8]]) -> Result<()> {
+        self.snapshot = Some((meta, chunks.iter().map(|chunk| chunk.to_vec()).collect()));
+        Ok(())
+    }
+
+    fn latest(&self) -> Result<Option<(SnapshotMeta, SnapshotData<'_>)>> {
+        Ok(self.snapshot.as_ref().map(|(meta, chunks)| {
+            let size = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+            (meta.clone(), SnapshotData::new(size, ChunksReader { chunks, offset: 0 }))
+        }))
+    }
+}
+
+/// Reads the chunks one after the other.
+struct ChunksReader<'a> {
+    chunks: &'a [Vec<u8>],
+    // The position in the first chunk.
+    offset: usize,
+}
+
+impl Read for ChunksReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some((chunk, rest)) = self.chunks.split_first() {
+            if self.offset < chunk.len() {
+                let n = buf.len().min(chunk.len() - self.offset);
+                buf[..n].copy_from_slice(&chunk[self.offset..self.offset + n]);
+                self.offset += n;
+                return Ok(n);
+            }
+            self.chunks = rest;
+            self.offset = 0;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::LogKind;
+
+    #[test]
+    fn delete_range() {
+        let mut store = MemoryStore::new();
+        let logs: Vec<_> = (1..=10)
+            .map(|index| Log { index, term: 1, kind: LogKind::Command, data: vec![index as u8] })
+            .collect();
+        store.store_logs(&logs).unwrap();
+
+        store.delete_range(1, 3).unwrap();
+        store.delete_range(8, 10).unwrap();
+
+        assert_eq!(store.first_index().unwrap(), 4);
+        assert_eq!(store.last_index().unwrap(), 7);
+        assert_eq!(store.get_log(3).unwrap(), None);
+        assert_eq!(store.get_log(5).unwrap().unwrap().data, vec![5]);
+    }
+
+    #[test]
+    fn snapshot_chunks_are_read_in_order() {
+        let mut store = MemoryStore::new();
+        assert!(store.latest().unwrap().is_none());
+
+        let meta = SnapshotMeta { index: 10, term: 1, configuration: vec![1, 2], configuration_index: 1 };
+        store.create(meta.clone(), &[&b"ab"[..], b"", b"cde"]).unwrap();
+
+        let (latest_meta, mut data) = store.latest().unwrap().unwrap();
+        assert_eq!(latest_meta, meta);
+        assert_eq!(data.size(), 5);
+        let mut bytes = Vec::new();
+        data.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, b"abcde");
+        assert_eq!(store.latest_meta().unwrap(), Some(meta));
+    }
+
+    #[test]
+    fn snapshot_without_chunks_is_empty() {
+        let mut store = MemoryStore::new();
+        let meta = SnapshotMeta { index: 10, term: 1, configuration: vec![1, 2], configuration_index: 1 };
+        store.create(meta.clone(), &[]).unwrap();
+
+        let (latest_meta, mut data) = store.latest().unwrap().unwrap();
+        assert_eq!(latest_meta, meta);
+        assert_eq!(data.size(), 0);
+        let mut bytes = Vec::new();
+        data.read_to_end(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+    }
+}
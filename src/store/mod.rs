@@ -0,0 +1,170 @@
+//! The persistence half of a `raft_io`, expressed as the same three interfaces
+//! as [the HashiCorp ones](https://pkg.go.dev/github.com/hashicorp/raft?tab=doc#LogStore).
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read};
+
+use canonical_raft_sys::*;
+
+use crate::error::{RaftError, Result};
+
+mod memory;
+
+pub use self::memory::MemoryStore;
+
+/// The key under which the current term is stored in the `StableStore`.
+pub const CURRENT_TERM_KEY: &[u8] = b"CurrentTerm";
+
+/// The key under which the server voted for in the current term is stored in the `StableStore`.
+pub const VOTED_FOR_KEY: &[u8] = b"LastVoteCand";
+
+/// The kind of a log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogKind {
+    /// A command given to `Fsm::apply`.
+    Command,
+    /// A no-op entry used to wait for all the previous entries to be applied.
+    Barrier,
+    /// A new cluster configuration.
+    Change,
+}
+
+impl LogKind {
+    pub fn from_raw(type_: u16) -> Result<LogKind> {
+        match type_ as u32 {
+            RAFT_COMMAND => Ok(LogKind::Command),
+            RAFT_BARRIER => Ok(LogKind::Barrier),
+            RAFT_CHANGE => Ok(LogKind::Change),
+            _ => Err(RaftError::Malformed(Some(format!("unknown entry type {}", type_)))),
+        }
+    }
+
+    pub fn to_raw(self) -> u16 {
+        let type_ = match self {
+            LogKind::Command => RAFT_COMMAND,
+            LogKind::Barrier => RAFT_BARRIER,
+            LogKind::Change => RAFT_CHANGE,
+        };
+        type_ as u16
+    }
+}
+
+/// A log entry along with its position in the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Log {
+    pub index: u64,
+    pub term: u64,
+    pub kind: LogKind,
+    pub data: Vec<u8>,
+}
+
+/// Describes a snapshot stored in a `SnapshotStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMeta {
+    /// The index of the last log entry included in the snapshot.
+    pub index: u64,
+    /// The term of the last log entry included in the snapshot.
+    pub term: u64,
+    /// The cluster configuration at the time of the snapshot, in the raft library format.
+    pub configuration: Vec<u8>,
+    /// The index of the log entry that introduced the configuration.
+    pub configuration_index: u64,
+}
+
+/// Stores and retrieves the log entries.
+///
+/// Indexes start at 1, a store that holds no entry returns 0 as its first and last index.
+pub trait LogStore {
+    /// Returns the index of the first entry written, 0 for no entries.
+    fn first_index(&self) -> Result<u64>;
+
+    /// Returns the index of the last entry written, 0 for no entries.
+    fn last_index(&self) -> Result<u64>;
+
+    /// Returns the entry at the given index, if any.
+    fn get_log(&self, index: u64) -> Result<Option<Log>>;
+
+    /// Stores multiple contiguous entries, overwriting any entry with the same index.
+    fn store_logs(&mut self, logs: &[Log]) -> Result<()>;
+
+    /// Deletes the entries in the given inclusive range.
+    fn delete_range(&mut self, min: u64, max: u64) -> Result<()>;
+}
+
+/// Stores the few values that must survive a restart, such as the current term.
+pub trait StableStore {
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Returns the value stored under the given key, if any.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn set_u64(&mut self, key: &[u8], value: u64) -> Result<()> {
+        self.set(key, &value.to_le_bytes())
+    }
+
+    /// Returns the integer stored under the given key, 0 if there is none.
+    fn get_u64(&self, key: &[u8]) -> Result<u64> {
+        match self.get(key)? {
+            Some(bytes) => match bytes.as_slice().try_into() {
+                Ok(bytes) => Ok(u64::from_le_bytes(bytes)),
+                Err(_) => Err(RaftError::Corrupt(Some(format!("invalid integer stored under {:?}", key)))),
+            },
+            None => Ok(0),
+        }
+    }
+}
+
+/// Stores the snapshots of the state machine, only the latest one is ever needed.
+pub trait SnapshotStore {
+    /// Stores a new snapshot, that replaces the previous ones.
+    ///
+    /// The data is given in the chunks the state machine spooled it into,
+    /// in order, they are never concatenated into a single buffer beforehand.
+    /// A state machine writing nothing gives no chunk at all, the snapshot is
+    /// then valid and its data empty.
+    fn create(&mut self, meta: SnapshotMeta, chunks: &[&[u8]]) -> Result<()>;
+
+    /// Opens the most recent snapshot, if any, its data is read from the store as it is consumed.
+    fn latest(&self) -> Result<Option<(SnapshotMeta, SnapshotData<'_>)>>;
+
+    /// Returns the metadata of the most recent snapshot, if any, without reading its data.
+    fn latest_meta(&self) -> Result<Option<SnapshotMeta>> {
+        Ok(self.latest()?.map(|(meta, _)| meta))
+    }
+}
+
+/// The data of a snapshot opened by `SnapshotStore::latest`, along with its size.
+pub struct SnapshotData<'a> {
+    size: u64,
+    reader: Box<dyn Read + 'a>,
+}
+
+impl<'a> SnapshotData<'a> {
+    /// Wraps a reader that yields exactly `size` bytes.
+    pub fn new<R: Read + 'a>(size: u64, reader: R) -> SnapshotData<'a> {
+        SnapshotData { size, reader: Box::new(reader) }
+    }
+
+    /// Returns the number of bytes of the data.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl fmt::Debug for SnapshotData<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SnapshotData").field("size", &self.size).finish()
+    }
+}
+
+impl Read for SnapshotData<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Everything an I/O backend needs to persist the raft state.
+pub trait Storage: LogStore + StableStore + SnapshotStore {}
+
+impl<S: LogStore + StableStore + SnapshotStore> Storage for S {}
@@ -0,0 +1,494 @@
+//! A deterministic in-memory cluster, to test state machines under partitions and crashes.
+//!
+//! The servers of a `Fixture` share a simulated clock and network, nothing happens
+//! until the cluster is explicitly stepped.
+
+use std::future::Future;
+use std::time::Duration;
+use std::mem;
+
+use bytes::Bytes;
+use canonical_raft_sys::*;
+use libc::{c_int, c_uint};
+
+use crate::configuration::Configuration;
+use crate::error::{RaftError, Result};
+use crate::fsm::{Fsm, FsmAdapter};
+use crate::state::{State, Status};
+
+/// The maximum number of servers in a fixture.
+pub const MAX_SERVERS: usize = 8;
+
+/// A cluster of in-memory servers, each one replicating its own `Fsm`.
+///
+/// Servers are identified by their index in the fixture, their raft id is this index plus one.
+pub struct Fixture<F: Fsm> {
+    raw: Box<raft_fixture>,
+    fsms: Vec<FsmAdapter<F>>,
+    // The raft instances keep pointers to these, they must never move.
+    raw_fsms: Vec<Box<[raft_fsm]>>,
+}
+
+/// The kind of an event fired by `Fixture::step`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Tick,
+    Network,
+    Disk,
+}
+
+/// An event fired by `Fixture::step`, on the server with the given index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub server_index: usize,
+    pub kind: EventKind,
+}
+
+/// The type of the messages exchanged by the servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    AppendEntries,
+    AppendEntriesResult,
+    RequestVote,
+    RequestVoteResult,
+    InstallSnapshot,
+    TimeoutNow,
+}
+
+impl MessageType {
+    fn to_raw(self) -> c_int {
+        let type_ = match self {
+            MessageType::AppendEntries => RAFT_IO_APPEND_ENTRIES,
+            MessageType::AppendEntriesResult => RAFT_IO_APPEND_ENTRIES_RESULT,
+            MessageType::RequestVote => RAFT_IO_REQUEST_VOTE,
+            MessageType::RequestVoteResult => RAFT_IO_REQUEST_VOTE_RESULT,
+            MessageType::InstallSnapshot => RAFT_IO_INSTALL_SNAPSHOT,
+            MessageType::TimeoutNow => RAFT_IO_TIMEOUT_NOW,
+        };
+        type_ as c_int
+    }
+}
+
+impl<F: Fsm> Fixture<F> {
+    /// Creates a cluster with one server per state machine, all connected to one another,
+    /// but neither bootstrapped nor started.
+    pub fn new(fsms: Vec<F>) -> Result<Fixture<F>> {
+        if fsms.is_empty() || fsms.len() > MAX_SERVERS {
+            return Err(RaftError::Invalid(Some(format!("a fixture must have between 1 and {} servers", MAX_SERVERS))));
+        }
+
+        let mut fsms: Vec<_> = fsms.into_iter().map(FsmAdapter::new).collect();
+        // The fixture expects an array of raft_fsm, the copies share the data pointer of the adapters.
+        let mut raw_fsm: Box<[raft_fsm]> = fsms.iter_mut().map(|fsm| unsafe { *fsm.as_raw() }).collect();
+
+        let mut raw: Box<raft_fixture> = Box::new(unsafe { mem::zeroed() });
+        let rv = unsafe { raft_fixture_init(&mut *raw, fsms.len() as c_uint, raw_fsm.as_mut_ptr()) };
+        if rv != 0 {
+            return Err(RaftError::from_code(rv));
+        }
+
+        Ok(Fixture { raw, fsms, raw_fsms: vec![raw_fsm] })
+    }
+
+    /// Bootstraps every server with a configuration made of all of them,
+    /// the first `n_voting` servers are voters, the others are standbys.
+    pub fn bootstrap(&mut self, n_voting: usize) -> Result<()> {
+        let mut configuration = Configuration::new();
+
+        let rv = unsafe { raft_fixture_configuration(self.as_raw(), n_voting as c_uint, configuration.as_raw()) };
+        if rv != 0 {
+            return Err(RaftError::from_code(rv));
+        }
+
+        let rv = unsafe { raft_fixture_bootstrap(self.as_raw(), configuration.as_raw()) };
+        if rv != 0 {
+            return Err(RaftError::from_code(rv));
+        }
+
+        Ok(())
+    }
+
+    /// Starts every server of the cluster.
+    pub fn start(&mut self) -> Result<()> {
+        let rv = unsafe { raft_fixture_start(self.as_raw()) };
+        if rv != 0 {
+            return Err(RaftError::from_code(rv));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of servers in the cluster.
+    pub fn n(&self) -> usize {
+        self.raw.n as usize
+    }
+
+    /// Returns the current time of the cluster, in milliseconds.
+    pub fn time(&self) -> u64 {
+        self.raw.time
+    }
+
+    /// Returns the state machine of the given server.
+    pub fn fsm(&self, i: usize) -> &F {
+        self.fsms[i].fsm()
+    }
+
+    /// Returns the captured outputs of the given server, for the batched commands.
+    #[cfg(test)]
+    pub(crate) fn captures(&mut self, i: usize) -> *mut crate::batch::Captures<F::Output> {
+        let raft = self.raft(i);
+        let captures = self.fsms[i].captures();
+        unsafe { (*captures).set_raft(raft) };
+        captures
+    }
+
+    /// Returns the raft instance of the given server.
+    pub fn raft(&mut self, i: usize) -> *mut raft {
+        self.check_index(i);
+        unsafe { raft_fixture_get(self.as_raw(), i as c_uint) }
+    }
+
+    /// Returns the state of the given server.
+    pub fn state(&mut self, i: usize) -> State {
+        State::from_raw(unsafe { raft_state(self.raft(i)) })
+    }
+
+    /// Returns a snapshot of the state of the given server.
+    pub fn status(&mut self, i: usize) -> Status {
+        unsafe { Status::from_raw(self.raft(i)) }
+    }
+
+    /// Returns `false` if the given server has been killed.
+    pub fn alive(&mut self, i: usize) -> bool {
+        self.check_index(i);
+        unsafe { raft_fixture_alive(self.as_raw(), i as c_uint) }
+    }
+
+    /// Returns the index of the current stable leader, if any.
+    pub fn leader_index(&mut self) -> Option<usize> {
+        let i = unsafe { raft_fixture_leader_index(self.as_raw()) } as usize;
+        if i < self.n() { Some(i) } else { None }
+    }
+
+    /// Returns the id of the server the given server voted for, if any.
+    pub fn voted_for(&mut self, i: usize) -> Option<u64> {
+        self.check_index(i);
+        match unsafe { raft_fixture_voted_for(self.as_raw(), i as c_uint) } {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Returns the commit index of the current stable leader.
+    pub fn commit_index(&self) -> u64 {
+        self.raw.commit_index
+    }
+
+    /// Proposes a command to the given server, that must be the leader.
+    ///
+    /// The returned future only resolves once the cluster has been stepped enough
+    /// for the command to be committed and applied.
+    pub fn apply(&mut self, i: usize, command: impl Into<Bytes>) -> impl Future<Output = Result<(F::Output, u64)>> {
+        let raft = self.raft(i);
+        unsafe { crate::raft::apply(raft, command.into()) }
+    }
+
+    /// Drives the cluster so that the given server gets elected as leader.
+    ///
+    /// There must be no leader nor candidate and the server must be a voter
+    /// connected to a majority of the voters.
+    pub fn elect(&mut self, i: usize) {
+        self.check_index(i);
+        unsafe { raft_fixture_elect(self.as_raw(), i as c_uint) }
+    }
+
+    /// Drives the cluster so that the current leader steps down.
+    pub fn depose(&mut self) {
+        unsafe { raft_fixture_depose(self.as_raw()) }
+    }
+
+    /// Advances the cluster until a single event fires.
+    pub fn step(&mut self) -> Event {
+        let event = unsafe { *raft_fixture_step(self.as_raw()) };
+        Event::from_raw(event)
+    }
+
+    /// Calls `step` exactly `n` times, returns the last fired event.
+    pub fn step_n(&mut self, n: usize) -> Event {
+        let event = unsafe { *raft_fixture_step_n(self.as_raw(), n as c_uint) };
+        Event::from_raw(event)
+    }
+
+    /// Steps the cluster until `stop` returns `true` or `max` has elapsed,
+    /// returns `true` if `stop` returned `true` in time.
+    pub fn step_until<P>(&mut self, max: Duration, mut stop: P) -> bool
+    where P: FnMut(&mut Fixture<F>) -> bool,
+    {
+        let deadline = self.time() + max.as_millis() as u64;
+        while !stop(self) {
+            if self.time() >= deadline {
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+
+    /// Steps the cluster until the given duration has elapsed.
+    pub fn step_until_elapsed(&mut self, duration: Duration) {
+        unsafe { raft_fixture_step_until_elapsed(self.as_raw(), millis(duration)) }
+    }
+
+    /// Steps the cluster until a leader is elected or `max` has elapsed.
+    pub fn step_until_has_leader(&mut self, max: Duration) -> bool {
+        unsafe { raft_fixture_step_until_has_leader(self.as_raw(), millis(max)) }
+    }
+
+    /// Steps the cluster until the current leader is deposed or `max` has elapsed.
+    pub fn step_until_has_no_leader(&mut self, max: Duration) -> bool {
+        unsafe { raft_fixture_step_until_has_no_leader(self.as_raw(), millis(max)) }
+    }
+
+    /// Steps the cluster until the given server, or every server if `None`,
+    /// has applied the entry at the given index or `max` has elapsed.
+    pub fn step_until_applied(&mut self, i: Option<usize>, index: u64, max: Duration) -> bool {
+        let i = match i {
+            Some(i) => {
+                self.check_index(i);
+                i
+            },
+            None => self.n(),
+        };
+        unsafe { raft_fixture_step_until_applied(self.as_raw(), i as c_uint, index, millis(max)) }
+    }
+
+    /// Steps the cluster until the given server is in the given state or `max` has elapsed.
+    pub fn step_until_state_is(&mut self, i: usize, state: State, max: Duration) -> bool {
+        self.check_index(i);
+        unsafe { raft_fixture_step_until_state_is(self.as_raw(), i as c_uint, state.to_raw(), millis(max)) }
+    }
+
+    /// Steps the cluster until the term of the given server is `term` or `max` has elapsed.
+    pub fn step_until_term_is(&mut self, i: usize, term: u64, max: Duration) -> bool {
+        self.check_index(i);
+        unsafe { raft_fixture_step_until_term_is(self.as_raw(), i as c_uint, term, millis(max)) }
+    }
+
+    /// Steps the cluster until the server `i` has voted for the server `j` or `max` has elapsed.
+    pub fn step_until_voted_for(&mut self, i: usize, j: usize, max: Duration) -> bool {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_step_until_voted_for(self.as_raw(), i as c_uint, j as c_uint, millis(max)) }
+    }
+
+    /// Steps the cluster until every message sent by the server `i` to the server `j`
+    /// has been delivered or `max` has elapsed.
+    pub fn step_until_delivered(&mut self, i: usize, j: usize, max: Duration) -> bool {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_step_until_delivered(self.as_raw(), i as c_uint, j as c_uint, millis(max)) }
+    }
+
+    /// Disconnects the server `i` from the server `j`, the messages sent by `i` to `j` fail.
+    pub fn disconnect(&mut self, i: usize, j: usize) {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_disconnect(self.as_raw(), i as c_uint, j as c_uint) }
+    }
+
+    /// Reconnects the server `i` to the server `j`.
+    pub fn reconnect(&mut self, i: usize, j: usize) {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_reconnect(self.as_raw(), i as c_uint, j as c_uint) }
+    }
+
+    /// Saturates the connection from the server `i` to the server `j`,
+    /// the messages sent by `i` to `j` are silently dropped.
+    pub fn saturate(&mut self, i: usize, j: usize) {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_saturate(self.as_raw(), i as c_uint, j as c_uint) }
+    }
+
+    /// Returns `true` if the connection from the server `i` to the server `j` is saturated.
+    pub fn saturated(&mut self, i: usize, j: usize) -> bool {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_saturated(self.as_raw(), i as c_uint, j as c_uint) }
+    }
+
+    /// Desaturates the connection from the server `i` to the server `j`.
+    pub fn desaturate(&mut self, i: usize, j: usize) {
+        self.check_index(i);
+        self.check_index(j);
+        unsafe { raft_fixture_desaturate(self.as_raw(), i as c_uint, j as c_uint) }
+    }
+
+    /// Kills the given server, it does not receive messages nor ticks anymore.
+    pub fn kill(&mut self, i: usize) {
+        self.check_index(i);
+        unsafe { raft_fixture_kill(self.as_raw(), i as c_uint) }
+    }
+
+    /// Adds a new empty server to the cluster, connected to all the others,
+    /// returns its index. It is not part of the configuration nor started.
+    pub fn grow(&mut self, fsm: F) -> Result<usize> {
+        if self.n() == MAX_SERVERS {
+            return Err(RaftError::TooMany(None));
+        }
+
+        let mut fsm = FsmAdapter::new(fsm);
+        let mut raw_fsm: Box<[raft_fsm]> = Box::new([unsafe { *fsm.as_raw() }]);
+
+        let rv = unsafe { raft_fixture_grow(self.as_raw(), raw_fsm.as_mut_ptr()) };
+        if rv != 0 {
+            return Err(RaftError::from_code(rv));
+        }
+
+        self.fsms.push(fsm);
+        self.raw_fsms.push(raw_fsm);
+
+        Ok(self.n() - 1)
+    }
+
+    /// Sets the election timeout that the given server draws, 1000 + i * 100 milliseconds by default.
+    pub fn set_randomized_election_timeout(&mut self, i: usize, timeout: Duration) {
+        self.check_index(i);
+        unsafe { raft_fixture_set_randomized_election_timeout(self.as_raw(), i as c_uint, millis(timeout)) }
+    }
+
+    /// Sets the time the messages sent by the given server take to be delivered, 15 milliseconds by default.
+    pub fn set_network_latency(&mut self, i: usize, latency: Duration) {
+        self.check_index(i);
+        unsafe { raft_fixture_set_network_latency(self.as_raw(), i as c_uint, millis(latency)) }
+    }
+
+    /// Sets the time the disk writes of the given server take, 10 milliseconds by default.
+    pub fn set_disk_latency(&mut self, i: usize, latency: Duration) {
+        self.check_index(i);
+        unsafe { raft_fixture_set_disk_latency(self.as_raw(), i as c_uint, millis(latency)) }
+    }
+
+    /// Sets the persisted term of the given server, it must not be started yet.
+    pub fn set_term(&mut self, i: usize, term: u64) {
+        self.check_index(i);
+        unsafe { raft_fixture_set_term(self.as_raw(), i as c_uint, term) }
+    }
+
+    /// Makes the given server fail its I/O requests, starting after `delay`
+    /// requests and for `repeat` requests.
+    pub fn io_fault(&mut self, i: usize, delay: u32, repeat: u32) {
+        self.check_index(i);
+        unsafe { raft_fixture_io_fault(self.as_raw(), i as c_uint, delay as c_int, repeat as c_int) }
+    }
+
+    /// Returns the number of messages of the given type successfully sent by the given server.
+    pub fn n_send(&mut self, i: usize, type_: MessageType) -> usize {
+        self.check_index(i);
+        unsafe { raft_fixture_n_send(self.as_raw(), i as c_uint, type_.to_raw()) as usize }
+    }
+
+    /// Returns the number of messages of the given type received by the given server.
+    pub fn n_recv(&mut self, i: usize, type_: MessageType) -> usize {
+        self.check_index(i);
+        unsafe { raft_fixture_n_recv(self.as_raw(), i as c_uint, type_.to_raw()) as usize }
+    }
+
+    /// Returns the underlying fixture, for the functions that are not wrapped yet.
+    pub fn as_raw(&mut self) -> *mut raft_fixture {
+        &mut *self.raw
+    }
+
+    fn check_index(&self, i: usize) {
+        assert!(i < self.n(), "server index {} out of bounds, the fixture has {} servers", i, self.n());
+    }
+}
+
+impl<F: Fsm> Drop for Fixture<F> {
+    fn drop(&mut self) {
+        // Closes every raft instance, the state machines are released after them.
+        unsafe { raft_fixture_close(self.as_raw()) }
+    }
+}
+
+impl Event {
+    fn from_raw(event: raft_fixture_event) -> Event {
+        let kind = match event.type_ as u32 {
+            RAFT_FIXTURE_TICK => EventKind::Tick,
+            RAFT_FIXTURE_NETWORK => EventKind::Network,
+            _ => EventKind::Disk,
+        };
+        Event { server_index: event.server_index as usize, kind }
+    }
+}
+
+fn millis(duration: Duration) -> c_uint {
+    duration.as_millis() as c_uint
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+
+    use futures::FutureExt;
+
+    use super::*;
+
+    /// A state machine adding the commands to a counter, shared by the tests of the crate.
+    pub(crate) struct Counter(pub(crate) u64);
+
+    impl Fsm for Counter {
+        type Output = u64;
+
+        fn apply(&mut self, command: &[u8]) -> Result<u64> {
+            let command = command.try_into().map_err(|_| RaftError::Malformed(None))?;
+            self.0 += u64::from_le_bytes(command);
+            Ok(self.0)
+        }
+
+        fn snapshot<W: Write>(&self, mut writer: W) -> Result<()> {
+            writer.write_all(&self.0.to_le_bytes())?;
+            Ok(())
+        }
+
+        fn restore<R: Read>(&mut self, mut reader: R) -> Result<()> {
+            let mut snapshot = [0; 8];
+            reader.read_exact(&mut snapshot)?;
+            self.0 = u64::from_le_bytes(snapshot);
+            Ok(())
+        }
+    }
+
+    /// Starts a cluster of three voters counting from zero, the first one
+    /// is elected and has applied every entry of the bootstrap.
+    pub(crate) fn cluster() -> Fixture<Counter> {
+        let mut fixture = Fixture::new(vec![Counter(0), Counter(0), Counter(0)]).unwrap();
+        fixture.bootstrap(3).unwrap();
+        fixture.start().unwrap();
+        fixture.elect(0);
+        let last_index = unsafe { raft_last_index(fixture.raft(0)) };
+        assert!(fixture.step_until_applied(Some(0), last_index, Duration::from_secs(2)));
+        fixture
+    }
+
+    #[test]
+    fn replicate_with_a_partitioned_follower() {
+        let mut fixture = cluster();
+
+        fixture.disconnect(0, 2);
+        fixture.disconnect(2, 0);
+
+        let applied = fixture.apply(0, 5u64.to_le_bytes().to_vec());
+        assert!(fixture.step_until(Duration::from_secs(2), |f| f.fsm(0).0 == 5 && f.fsm(1).0 == 5));
+        let (output, index) = applied.now_or_never().unwrap().unwrap();
+        assert_eq!(output, 5);
+        assert_eq!(fixture.fsm(2).0, 0);
+
+        fixture.reconnect(0, 2);
+        fixture.reconnect(2, 0);
+        assert!(fixture.step_until_applied(None, index, Duration::from_secs(2)));
+        assert_eq!(fixture.fsm(2).0, 5);
+    }
+}
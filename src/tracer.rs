@@ -0,0 +1,84 @@
+use std::ffi::CStr;
+use std::ptr;
+
+use canonical_raft_sys::*;
+use libc::{c_char, c_int, c_void};
+
+/// Forwards the diagnostics emitted by the raft library to the `tracing` crate,
+/// or to the `log` facade when the `tracing` feature is disabled.
+///
+/// Every message is emitted at the debug level under the `canonical_raft` target,
+/// along with the file and line it comes from, the id of the server and its current term.
+pub struct Tracer {
+    // Boxed as the raft library keeps a pointer to the raft_tracer.
+    inner: Box<Inner>,
+}
+
+struct Inner {
+    raw: raft_tracer,
+    // The raft instance the tracer is installed on, to decorate the messages.
+    raft: *const raft,
+}
+
+impl Tracer {
+    pub fn new() -> Tracer {
+        let raw = raft_tracer { impl_: ptr::null_mut(), emit: Some(tracer_emit) };
+        let mut inner = Box::new(Inner { raw, raft: ptr::null() });
+        inner.raw.impl_ = &mut *inner as *mut Inner as *mut c_void;
+
+        Tracer { inner }
+    }
+
+    /// Attaches the tracer to the given raft instance, returns the `raft_tracer` to install on it.
+    pub(crate) fn attach(&mut self, raft: *const raft) -> *mut raft_tracer {
+        self.inner.raft = raft;
+        &mut self.inner.raw
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
+        Tracer::new()
+    }
+}
+
+unsafe extern "C" fn tracer_emit(
+    t: *mut raft_tracer,
+    file: *const c_char,
+    line: c_int,
+    message: *const c_char,
+)
+{
+    let inner = &*((*t).impl_ as *const Inner);
+    let (id, term) = match inner.raft.as_ref() {
+        Some(raft) => (raft.id, raft.current_term),
+        None => (0, 0),
+    };
+
+    let file = CStr::from_ptr(file).to_string_lossy();
+    let message = CStr::from_ptr(message).to_string_lossy();
+
+    emit(&file, line as u32, id, term, &message);
+}
+
+#[cfg(feature = "tracing")]
+fn emit(file: &str, line: u32, id: raft_id, term: raft_term, message: &str) {
+    tracing::debug!(target: "canonical_raft", file, line, server_id = id, term, "{}", message);
+}
+
+#[cfg(not(feature = "tracing"))]
+fn emit(file: &str, line: u32, id: raft_id, term: raft_term, message: &str) {
+    if !log::log_enabled!(target: "canonical_raft", log::Level::Debug) {
+        return;
+    }
+
+    log::logger().log(
+        &log::Record::builder()
+            .args(format_args!("[server {} term {}] {}", id, term, message))
+            .level(log::Level::Debug)
+            .target("canonical_raft")
+            .file(Some(file))
+            .line(Some(line))
+            .build(),
+    );
+}
@@ -0,0 +1,116 @@
+use std::ffi::CStr;
+use std::future::Future;
+use std::{mem, ptr};
+
+use canonical_raft_sys::*;
+use futures_channel::oneshot;
+use libc::{c_char, c_void};
+
+use crate::barrier::{leader_hint, not_leader};
+use crate::error::{RaftError, Result};
+use crate::state::{progress, Role, State};
+
+/// Transfers the leadership of the given raft instance to the given voter,
+/// or to the most up-to-date one if none is given.
+pub(crate) unsafe fn transfer(raft: *mut raft, id: Option<u64>) -> impl Future<Output = Result<(u64, String)>> {
+    let (sender, receiver) = oneshot::channel();
+    let result = submit_transfer(raft, id, sender);
+
+    async move {
+        result?;
+        match receiver.await {
+            Ok(result) => result,
+            Err(oneshot::Canceled) => Err(RaftError::Canceled(None)),
+        }
+    }
+}
+
+unsafe fn submit_transfer(raft: *mut raft, id: Option<u64>, sender: oneshot::Sender<Result<(u64, String)>>) -> Result<()> {
+    if State::from_raw(raft_state(raft)) != State::Leader {
+        return Err(not_leader(raft));
+    }
+
+    let id = match id {
+        Some(id) => id,
+        None => most_up_to_date_voter(raft).ok_or(RaftError::NotFound(None))?,
+    };
+
+    let req = Box::into_raw(Box::new(TransferRequest { raw: mem::zeroed(), raft, sender }));
+    (*req).raw.data = req as *mut c_void;
+
+    let rv = raft_transfer(raft, &mut (*req).raw, id, Some(transfer_cb));
+    if rv != 0 {
+        drop(Box::from_raw(req));
+        return Err(RaftError::from_raft(rv, raft));
+    }
+
+    Ok(())
+}
+
+/// Returns the voter, other than the leader itself, whose log matches the most entries.
+unsafe fn most_up_to_date_voter(raft: *mut raft) -> Option<u64> {
+    progress(raft)
+        .filter(|(server, _)| server.id != (*raft).id && server.role == Role::Voter.to_raw())
+        .max_by_key(|(_, progress)| progress.match_index)
+        .map(|(server, _)| server.id)
+}
+
+struct TransferRequest {
+    raw: raft_transfer,
+    raft: *mut raft,
+    sender: oneshot::Sender<Result<(u64, String)>>,
+}
+
+unsafe extern "C" fn transfer_cb(req: *mut raft_transfer) {
+    let req = Box::from_raw((*req).data as *mut TransferRequest);
+
+    // The callback fires either once the target is the leader or when the transfer timed out.
+    let mut id = 0;
+    let mut address: *const c_char = ptr::null();
+    raft_leader(req.raft, &mut id, &mut address);
+
+    let result = if id != 0 && id == req.raw.id && !address.is_null() {
+        Ok((id, CStr::from_ptr(address).to_string_lossy().into_owned()))
+    } else {
+        let message = format!("server {} did not take the leadership in time, {}", req.raw.id, leader_hint(req.raft));
+        Err(RaftError::Timeout(Some(message)))
+    };
+
+    let _ = req.sender.send(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing::tests::cluster;
+
+    #[test]
+    fn failed_transfer_names_the_leader() {
+        let mut fixture = cluster();
+        fixture.disconnect(0, 1);
+        fixture.disconnect(1, 0);
+
+        let mut transferred = Box::pin(unsafe { transfer(fixture.raft(0), Some(2)) });
+        let mut result = None;
+        assert!(fixture.step_until(Duration::from_secs(10), |_| {
+            result = (&mut transferred).now_or_never();
+            result.is_some()
+        }));
+
+        let hint = unsafe { leader_hint(fixture.raft(0)) };
+        match result.unwrap() {
+            Err(RaftError::Timeout(Some(message))) => assert!(message.ends_with(&hint)),
+            other => panic!("unexpected transfer result {:?}", other),
+        }
+
+        let refused = unsafe { transfer(fixture.raft(1), None) };
+        match refused.now_or_never() {
+            Some(Err(RaftError::NotLeader(Some(message)))) => assert!(message.starts_with("the leader is")),
+            other => panic!("unexpected transfer result {:?}", other),
+        }
+    }
+}
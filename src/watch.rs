@@ -0,0 +1,79 @@
+use std::ptr;
+
+use canonical_raft_sys::*;
+use futures_channel::mpsc;
+use libc::{c_char, c_int, c_uint};
+
+use crate::state::{State, Status};
+
+/// A transition of a raft server, as yielded by `Raft::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    pub from: State,
+    pub to: State,
+    /// The term of the server once the transition happened.
+    pub term: u64,
+    /// The id and address of the leader known after the transition, if any.
+    pub leader: Option<(u64, String)>,
+}
+
+pub(crate) type IoStart = unsafe extern "C" fn(*mut raft_io, c_uint, raft_io_tick_cb, raft_io_recv_cb) -> c_int;
+pub(crate) type IoSend = unsafe extern "C" fn(*mut raft_io, *mut raft_io_send, *const raft_message, raft_io_send_cb) -> c_int;
+
+/// Observes the state of a raft instance after each tick and received message.
+///
+/// It sits between the raft library and its I/O backend, the callbacks given
+/// by the raft library to the backend are saved here and wrapped, along with
+/// the send function of the backend.
+pub(crate) struct Watch {
+    pub(crate) start: Option<IoStart>,
+    pub(crate) send: Option<IoSend>,
+    pub(crate) tick: raft_io_tick_cb,
+    pub(crate) recv: raft_io_recv_cb,
+    state: State,
+    // The id of the known leader, 0 if none.
+    leader: u64,
+    subscribers: Vec<mpsc::UnboundedSender<StateChange>>,
+}
+
+impl Watch {
+    pub(crate) fn new() -> Watch {
+        Watch {
+            start: None,
+            send: None,
+            tick: None,
+            recv: None,
+            state: State::Unavailable,
+            leader: 0,
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn subscribe(&mut self) -> mpsc::UnboundedReceiver<StateChange> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Notifies the subscribers if the state or the leader of the raft instance changed.
+    pub(crate) unsafe fn observe(&mut self, raft: *mut raft) {
+        // This runs after every tick and message, the status and its strings
+        // are only built once the raw state or leader changed.
+        let state = State::from_raw(raft_state(raft));
+        let mut leader_id = 0;
+        let mut leader_address: *const c_char = ptr::null();
+        raft_leader(raft, &mut leader_id, &mut leader_address);
+        let leader = if leader_address.is_null() { 0 } else { leader_id };
+        if state == self.state && leader == self.leader {
+            return;
+        }
+
+        let status = Status::from_raw(raft);
+        let change = StateChange { from: self.state, to: status.state, term: status.current_term, leader: status.leader };
+        self.state = change.to;
+        self.leader = leader;
+
+        // The subscribers that dropped their stream are forgotten.
+        self.subscribers.retain(|sender| sender.unbounded_send(change.clone()).is_ok());
+    }
+}